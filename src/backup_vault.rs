@@ -2,22 +2,54 @@ extern crate time;
 
 use std::fs;
 use std::fs::File;
-use std::path::PathBuf;
-use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::io::Read;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
 use chrono::{DateTime, Utc};
 
 use uuid::Uuid;
 use serde::{Serialize, Deserialize};
+use zeroize::Zeroizing;
 
 use rayon::prelude::*;
 
+use crate::backend::*;
+use crate::compression::*;
 use crate::crypto::*;
+use crate::filter::FileFilter;
+use crate::kdf::KdfParams;
+use crate::keyfile::Keyfile;
+use crate::snapshot::*;
 
-const BUF_SIZE: usize = 4*1024*1024;
+pub(crate) const BUF_SIZE: usize = 4*1024*1024;
+
+/// Blobs start with this magic, a format version and the codec byte, followed by the sealed payload.
+/// Blobs without the magic predate the header and are encrypted with the vault nonce.
+const BLOB_MAGIC: &[u8; 3] = b"QBB";
+const BLOB_VERSION: u8 = 1;
+const BLOB_HEADER_SIZE: usize = BLOB_MAGIC.len() + 2;
+
+/// Vaults without a version in their config predate versioning and can hold any older layout,
+/// see `migrate`. Vaults with a newer version or unknown features are refused.
+pub const VAULT_FORMAT_VERSION: u32 = 1;
+
+/// The key is only stored in key slots, the index holds no key material
+const FEATURE_KEY_SLOTS: &str = "key_slots";
+/// Every blob has a header and its own nonce
+const FEATURE_BLOB_HEADER: &str = "blob_header";
+/// Blob payloads may be zstd compressed
+const FEATURE_ZSTD: &str = "zstd";
+
+const SUPPORTED_FEATURES: &[&str] = &[FEATURE_KEY_SLOTS, FEATURE_BLOB_HEADER, FEATURE_ZSTD];
+
+/// All clients take the lock under the same name, so only one of them can create it.
+const LOCK_NAME: &str = "exclusive";
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum BackupError {
     VaultDoesNotExist,
     VaultReadError,
@@ -26,82 +58,318 @@ pub enum BackupError {
     VaultFileOpenError,
     VaultFileReadError,
     VaultFileCopyError,
+    VaultLocked,
+    VaultAccessDenied,
+    VaultConnectionError,
+    VaultInvalidLocation,
+    VaultDecryptionError,
+    VaultPasswordRequired,
+    VaultKeyfileRequired,
+    VaultUnsupportedVersion,
+}
+
+impl From<BackendError> for BackupError {
+    fn from(err: BackendError) -> Self {
+        match err {
+            BackendError::NotFound => BackupError::VaultDoesNotExist,
+            BackendError::AlreadyExists => BackupError::VaultCreationError,
+            BackendError::InvalidName | BackendError::InvalidLocation => BackupError::VaultInvalidLocation,
+            BackendError::Unauthorized | BackendError::Forbidden => BackupError::VaultAccessDenied,
+            BackendError::ConnectionError => BackupError::VaultConnectionError,
+            BackendError::IoError => BackupError::VaultReadError,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl std::fmt::Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            BackupError::VaultDoesNotExist => "the vault does not exist",
+            BackupError::VaultReadError => "failed to read the vault",
+            BackupError::VaultCreationError => "failed to create the vault",
+            BackupError::VaultWrongPassword => "wrong password or keyfile",
+            BackupError::VaultFileOpenError => "failed to open a file",
+            BackupError::VaultFileReadError => "failed to read a file",
+            BackupError::VaultFileCopyError => "failed to copy a file into the vault",
+            BackupError::VaultLocked => "the vault is locked by another process",
+            BackupError::VaultAccessDenied => "access to the vault was denied",
+            BackupError::VaultConnectionError => "failed to connect to the vault",
+            BackupError::VaultInvalidLocation => "the vault location is invalid",
+            BackupError::VaultDecryptionError => "failed to decrypt the vault, it may be damaged",
+            BackupError::VaultPasswordRequired => "the vault needs its password in addition to the keyfile",
+            BackupError::VaultKeyfileRequired => "the vault is unlocked with a keyfile",
+            BackupError::VaultUnsupportedVersion => "the vault was written by a newer version of quicky_backup",
+        };
+
+        f.write_str(message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct VaultFile {
     pub file_name: String,
     pub file_hash: String,
     pub file_path: PathBuf,
     pub file_size: u64,
     pub vault_paths: Vec<PathBuf>,
+    /// Modification time in unix seconds, missing in snapshots of older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_modified: Option<u64>,
+    /// Unix permission bits, missing in snapshots of older versions and on other platforms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_mode: Option<u32>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Metadata fields are missing in snapshots of older versions and are left out while empty,
+/// so these snapshots serialize unchanged, see `server::is_index_append`.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Snapshot {
     pub snapshot_id: String,
     pub snapshot_time: String,
-    pub snapshot_files: Vec<VaultFile>
+    pub snapshot_files: Vec<VaultFile>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hostname: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub username: String,
+    /// The backed up paths as given on the command line, made absolute
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paths: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub program_version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+pub struct BackupOptions {
+    pub compression: Compression,
+    pub filter: FileFilter,
+    pub tags: Vec<String>,
+    pub description: Option<String>,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        Self {
+            compression: Compression::Auto,
+            filter: FileFilter::default(),
+            tags: vec![],
+            description: None,
+        }
+    }
+}
+
+/// What is needed to unlock a vault.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyMode {
+    #[default]
+    Password,
+    Keyfile,
+    KeyfileAndPassword,
+}
+
+/// A password, a keyfile or both, depending on the `KeyMode` of the vault. Both are zeroed when dropped.
+#[derive(Clone, Default)]
+pub struct VaultKey {
+    pub password: Option<Zeroizing<String>>,
+    pub keyfile: Option<Keyfile>,
+}
+
+impl VaultKey {
+    pub fn from_password(password: Zeroizing<String>) -> VaultKey {
+        VaultKey {
+            password: Some(password),
+            keyfile: None,
+        }
+    }
+}
+
+/// The vault key sealed with a key derived from a password and/or keyfile, see `CryptoModule::wrap_key`.
+/// Unlocking a slot is what verifies the password or keyfile.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeySlot {
+    pub key_mode: KeyMode,
+    /// The password KDF, slots without one use the parameters vaults had before they were configurable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<KdfParams>,
+    /// Base64 of the KDF salt, the nonce and the sealed vault key
+    pub wrapped_key: String,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct VaultConfig {
+    /// 0 for vaults created before the format was versioned
+    #[serde(default)]
+    pub version: u32,
+    /// The format features the vault relies on, a binary has to support all of them to open it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    /// Vaults created before key slots verify their password with this bcrypt hash
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub password_hash: String,
+    #[serde(default)]
+    pub key_mode: KeyMode,
+    /// See `CryptoModule::key_check`, it lets an exported key unlock the vault on its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_check: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub key_slots: Vec<KeySlot>,
+    /// Served vaults only accept new snapshots and data, see `server::serve`
+    #[serde(default)]
+    pub append_only: bool,
+}
+
+/// The part of the vault stored in `vault.json`.
+#[derive(Deserialize)]
+struct VaultIndex {
+    snapshots: Vec<Snapshot>,
+    files: Vec<VaultFile>,
+    crypto: CryptoParams,
+}
+
+#[derive(Serialize)]
+struct VaultIndexRef<'a> {
+    snapshots: &'a Vec<Snapshot>,
+    files: &'a Vec<VaultFile>,
+    crypto: CryptoParams,
 }
 
 #[derive(Serialize, Deserialize)]
+struct VaultLock {
+    lock_id: String,
+    lock_time: String,
+    hostname: String,
+    pid: u32,
+}
+
 pub struct BackupVault {
-    pub vault_path: PathBuf,
-    pub password: String,
     pub snapshots: Vec<Snapshot>,
     pub files: Vec<VaultFile>,
     pub crypto: CryptoModule,
+    pub config: VaultConfig,
+    backend: Box<dyn Backend>,
+    lock_id: Option<String>,
 }
 
 impl BackupVault {
-    pub fn new(vault_path: PathBuf, password: String) -> Self {
+    pub fn new(crypto: CryptoModule, config: VaultConfig, backend: Box<dyn Backend>) -> Self {
         Self {
-            vault_path,
-            password: password.clone(),
             snapshots: vec![],
             files: vec![],
-            crypto: CryptoModule::new(password.as_bytes()),
+            crypto,
+            config,
+            backend,
+            lock_id: None,
         }
     }
 
-    pub fn create(vault_path: &PathBuf, password: &String) -> Result<BackupVault, BackupError> {
-        if vault_path.exists() && vault_path.is_dir() && !is_directory_empty(&vault_path) {
-            println!("Vault already exists and is not empty");
+    /// Creates a vault with a random key, stored in a key slot wrapped by the password, the keyfile
+    /// or both of them, whichever `key` contains. Keyfile slots skip the password KDF.
+    pub fn create(vault_path: &Path, key: &VaultKey, kdf: &KdfParams) -> Result<BackupVault, BackupError> {
+        let key_mode = match (&key.keyfile, &key.password) {
+            (Some(_), Some(_)) => KeyMode::KeyfileAndPassword,
+            (Some(_), None) => KeyMode::Keyfile,
+            (None, Some(_)) => KeyMode::Password,
+            (None, None) => return Err(BackupError::VaultPasswordRequired),
+        };
+
+        if key.password.is_some() && kdf.validate().is_err() {
             return Err(BackupError::VaultCreationError);
         }
 
-        if !vault_path.exists() {
-            let create_result = fs::create_dir_all(vault_path);
+        let backend = open_backend(vault_path)?;
 
-            if create_result.is_err() {
-                println!("Failed to create vault directory, {}", create_result.err().unwrap());
-                return Err(BackupError::VaultCreationError);
-            }
+        if let Err(err) = backend.create() {
+            println!("Failed to create vault {}", backend.location());
+            return Err(err.into());
         }
 
-        let config_path = vault_path.join("vault_config.json");
+        let crypto = CryptoModule::generate();
+        let key_slot = new_key_slot(&crypto, key_mode, key, kdf)?;
 
-        
-        let hash = bcrypt::hash(password, bcrypt::DEFAULT_COST).unwrap();        
         let config = VaultConfig {
-            password_hash: hash,
+            version: VAULT_FORMAT_VERSION,
+            features: current_features(),
+            password_hash: String::new(),
+            key_mode,
+            key_check: Some(crypto.key_check()),
+            key_slots: vec![key_slot],
+            append_only: false,
         };
 
-        let config_file = File::create(config_path);
+        let vault = BackupVault::new(crypto, config, backend);
+        vault.save_config()?;
+        vault.save_index()?;
 
-        if config_file.is_err() {
-            println!("Failed to create config file, {}", config_file.err().unwrap());
-            return Err(BackupError::VaultCreationError);
+        Ok(vault)
+    }
+
+    /// Whether a vault also needs its password to be opened with `keyfile`, so that the password is only asked for when needed.
+    pub fn needs_password(vault_path: &Path, keyfile: &Keyfile) -> Result<bool, BackupError> {
+        let backend = open_backend(vault_path)?;
+        let config = read_config(backend.as_ref())?;
+
+        // An exported key unlocks the vault on its own
+        Ok(config.key_mode == KeyMode::KeyfileAndPassword && config.key_check != Some(key_check(keyfile.key())))
+    }
+
+    /// Opens a vault with its password, its keyfile or both. A keyfile alone is also accepted
+    /// by any vault whose key it is, see `export_key`.
+    pub fn open(vault_path: &Path, key: &VaultKey) -> Result<BackupVault, BackupError> {
+        let backend = open_backend(vault_path)?;
+        let config = read_config(backend.as_ref())?;
+
+        if config.version > VAULT_FORMAT_VERSION {
+            println!("The vault has format version {}, this version of quicky_backup supports up to version {}", config.version, VAULT_FORMAT_VERSION);
+            return Err(BackupError::VaultUnsupportedVersion);
         }
 
-        let mut config_file = config_file.unwrap();
+        if let Some(feature) = config.features.iter().find(|feature| !SUPPORTED_FEATURES.contains(&feature.as_str())) {
+            println!("The vault uses the format feature {}, which this version of quicky_backup doesn't support", feature);
+            return Err(BackupError::VaultUnsupportedVersion);
+        }
+
+        match (&key.keyfile, &key.password, config.key_mode) {
+            (None, _, KeyMode::Keyfile | KeyMode::KeyfileAndPassword) => return Err(BackupError::VaultKeyfileRequired),
+            (None, None, KeyMode::Password) => return Err(BackupError::VaultPasswordRequired),
+            _ => {},
+        }
+
+        let vault = read_index(backend.as_ref())?;
+
+        let crypto = match config.key_slots.is_empty() {
+            true => open_legacy_key(&config, key, &vault.crypto)?,
+            false => open_key_slots(&config, key, &vault.crypto)?,
+        };
+
+        Ok(BackupVault {
+            snapshots: vault.snapshots,
+            files: vault.files,
+            crypto,
+            config,
+            backend,
+            lock_id: None,
+        })
+    }
+
+    /// Writes the vault key to a new keyfile that unlocks the vault on its own, whatever its key mode.
+    /// Vaults created before key slots only open with their password until they are migrated.
+    pub fn export_key(&self, keyfile_path: &Path) -> Result<(), BackupError> {
+        if self.config.key_slots.is_empty() {
+            println!("The vault was created before key slots, run migrate before exporting its key");
+            return Err(BackupError::VaultUnsupportedVersion);
+        }
+
+        match self.crypto.export_key().write(keyfile_path) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(BackupError::VaultFileOpenError),
+        }
+    }
 
-        let config_json_data = serde_json::to_string(&config);
+    pub(crate) fn save_config(&self) -> Result<(), BackupError> {
+        let config_json_data = serde_json::to_string(&self.config);
 
         if config_json_data.is_err() {
             println!("Failed to serialize config data");
@@ -110,76 +378,202 @@ impl BackupVault {
 
         let config_json_data = config_json_data.unwrap();
 
-        let write_result = config_file.write_all(config_json_data.as_bytes());
-
-        if write_result.is_err() {
+        if let Err(err) = self.backend.write(ObjectKind::Config, "", config_json_data.as_bytes()) {
             println!("Failed to write config data");
-            return Err(BackupError::VaultCreationError);
+            return Err(err.into());
         }
 
-        Ok(BackupVault::new(vault_path.clone(), password.clone()))
-
+        Ok(())
     }
 
-    pub fn open(vault_path: &PathBuf, password: &String) -> Result<BackupVault, BackupError> {
-        if !vault_path.exists() || !vault_path.is_dir() {
-            return Err(BackupError::VaultDoesNotExist);
+    /// In append-only mode a served vault refuses deletes and rewrites of existing data unless
+    /// the request comes from an admin. Local access to the vault is never restricted.
+    pub fn set_append_only(&mut self, append_only: bool) -> Result<(), BackupError> {
+        let previous = self.config.append_only;
+        self.config.append_only = append_only;
+
+        if let Err(err) = self.save_config() {
+            self.config.append_only = previous;
+            return Err(err);
         }
 
-        let config_path = vault_path.join("vault_config.json");
+        Ok(())
+    }
 
-        if !config_path.exists() {
-            return Err(BackupError::VaultReadError);
+    pub(crate) fn save_index(&self) -> Result<(), BackupError> {
+        let index = VaultIndexRef {
+            snapshots: &self.snapshots,
+            files: &self.files,
+            crypto: self.crypto.params(),
+        };
+
+        let vault_json_data = serde_json::to_string(&index);
+
+        if vault_json_data.is_err() {
+            println!("Failed to serialize vault data");
+            return Err(BackupError::VaultFileOpenError);
         }
 
-        let config_file = File::open(config_path);
+        let vault_json_data = vault_json_data.unwrap();
 
-        if config_file.is_err() {
-            return Err(BackupError::VaultReadError);
+        if let Err(err) = self.backend.write(ObjectKind::Index, "", vault_json_data.as_bytes()) {
+            println!("Failed to write vault data");
+            return Err(err.into());
         }
 
-        let config_file = config_file.unwrap();
+        Ok(())
+    }
 
-        let config: VaultConfig = match serde_json::from_reader(config_file) {
-            Ok(config) => config,
-            Err(_) => return Err(BackupError::VaultReadError),
+    /// Takes an exclusive lock on the vault for operations that modify it, and reads the index again,
+    /// so that snapshots other clients saved since the vault was opened aren't lost by saving it.
+    pub(crate) fn lock(&mut self) -> Result<(), BackupError> {
+        let lock = VaultLock {
+            lock_id: Uuid::new_v4().to_string(),
+            lock_time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs().to_string(),
+            hostname: sysinfo::System::host_name().unwrap_or_default(),
+            pid: std::process::id(),
         };
 
-        let is_password_correct = bcrypt::verify(password, &config.password_hash);
+        match self.backend.write_new(ObjectKind::Lock, LOCK_NAME, &serde_json::to_vec(&lock).unwrap()) {
+            Ok(_) => {},
+            Err(BackendError::AlreadyExists) => {
+                match self.read_lock() {
+                    Some(holder) => println!("Vault is locked by process {} on {}", holder.pid, holder.hostname),
+                    None => println!("Vault is locked by another process"),
+                }
+
+                println!("If no other backup is running, remove stale locks with the unlock command");
+                return Err(BackupError::VaultLocked);
+            },
+            Err(err) => return Err(err.into()),
+        }
+
+        self.lock_id = Some(lock.lock_id);
 
-        if is_password_correct.is_err() {
-            return Err(BackupError::VaultReadError);
+        match read_index(self.backend.as_ref()) {
+            Ok(index) => {
+                self.snapshots = index.snapshots;
+                self.files = index.files;
+
+                Ok(())
+            },
+            Err(err) => {
+                self.unlock();
+                Err(err)
+            }
         }
-        let is_password_correct = is_password_correct.unwrap();
+    }
+
+    pub(crate) fn unlock(&mut self) {
+        if let Some(lock_id) = self.lock_id.take() {
+            // Someone may have removed a lock they took for stale and locked the vault themselves since
+            if self.read_lock().is_some_and(|lock| lock.lock_id != lock_id) {
+                println!("Vault lock {} was removed by another process", lock_id);
+                return;
+            }
 
-        if !is_password_correct {
-            println!("Wrong password:\n{}\n{} != {}", password, config.password_hash, bcrypt::hash(password, bcrypt::DEFAULT_COST).unwrap());
-            return Err(BackupError::VaultWrongPassword);
+            if self.backend.delete(ObjectKind::Lock, LOCK_NAME).is_err() {
+                println!("Failed to remove vault lock {}", lock_id);
+            }
         }
+    }
 
-        let vault_file = File::open(vault_path.join("vault.json"));
+    fn read_lock(&self) -> Option<VaultLock> {
+        let data = self.backend.read(ObjectKind::Lock, LOCK_NAME).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
 
-        if vault_file.is_err() {
-            return Err(BackupError::VaultReadError);
+    /// Removes all locks, including the ones left behind by interrupted processes.
+    pub fn remove_locks(&self) -> Result<(), BackupError> {
+        for lock_id in self.backend.list(ObjectKind::Lock)? {
+            self.backend.delete(ObjectKind::Lock, &lock_id)?;
+            println!("Removed lock {}", lock_id);
         }
 
-        let vault_file = vault_file.unwrap();
+        Ok(())
+    }
 
-        let vault: BackupVault = match serde_json::from_reader(vault_file) {
-            Ok(vault) => vault,
-            Err(_) => return Err(BackupError::VaultReadError),
+    pub(crate) fn has_blob(&self, vault_path: &Path) -> Result<bool, BackupError> {
+        Ok(self.backend.exists(ObjectKind::Blob, &blob_name(vault_path))?)
+    }
+
+    /// Whether a blob predates the blob header and is encrypted with the vault nonce.
+    pub(crate) fn is_legacy_blob(&self, vault_path: &Path) -> Result<bool, BackupError> {
+        let buffer = self.backend.read(ObjectKind::Blob, &blob_name(vault_path))?;
+
+        Ok(!(buffer.len() >= BLOB_HEADER_SIZE && buffer.starts_with(BLOB_MAGIC)))
+    }
+
+    /// Reads and decrypts a blob, returning its payload which may still be compressed.
+    pub(crate) fn read_blob_payload(&self, vault_path: &Path) -> Result<(Codec, Vec<u8>), BackupError> {
+        let buffer = self.backend.read(ObjectKind::Blob, &blob_name(vault_path))?;
+
+        let (codec, plaintext) = if buffer.len() >= BLOB_HEADER_SIZE && buffer.starts_with(BLOB_MAGIC) {
+            let codec = match Codec::from_byte(buffer[BLOB_MAGIC.len() + 1]) {
+                Ok(codec) if buffer[BLOB_MAGIC.len()] == BLOB_VERSION => codec,
+                _ => return Err(BackupError::VaultDecryptionError),
+            };
+
+            (codec, self.crypto.open(&buffer[BLOB_HEADER_SIZE..]))
+        } else {
+            (Codec::None, self.crypto.decrypt(&buffer))
         };
 
-        Ok(BackupVault {
-            vault_path: vault_path.clone(),
-            password: password.clone(),
-            snapshots: vault.snapshots,
-            files: vault.files,
-            crypto: CryptoModule::import(password.as_bytes(), vault.crypto.export()),
-        })
+        match plaintext {
+            Ok(plaintext) => Ok((codec, plaintext)),
+            Err(_) => Err(BackupError::VaultDecryptionError),
+        }
+    }
+
+    /// Reads, decrypts and decompresses a blob.
+    pub(crate) fn read_blob(&self, vault_path: &Path) -> Result<Vec<u8>, BackupError> {
+        let (codec, payload) = self.read_blob_payload(vault_path)?;
+
+        match decompress(codec, payload) {
+            Ok(buffer) => Ok(buffer),
+            Err(_) => Err(BackupError::VaultDecryptionError),
+        }
+    }
+
+    /// Encrypts and writes a blob payload that is already compressed with `codec`.
+    pub(crate) fn write_blob_payload(&self, vault_path: &Path, codec: Codec, payload: &[u8]) -> Result<(), BackupError> {
+        let mut buffer = BLOB_MAGIC.to_vec();
+        buffer.push(BLOB_VERSION);
+        buffer.push(codec as u8);
+        buffer.extend(self.crypto.seal(payload));
+
+        Ok(self.backend.write(ObjectKind::Blob, &blob_name(vault_path), &buffer)?)
+    }
+
+    /// Compresses, encrypts and writes a blob, `file_name` helps recognizing compressed formats.
+    pub(crate) fn write_blob(&self, vault_path: &Path, plaintext: &[u8], file_name: &str, compression: Compression) -> Result<(), BackupError> {
+        let (codec, payload) = compress(plaintext, file_name, compression);
+
+        self.write_blob_payload(vault_path, codec, &payload)
+    }
+
+    pub(crate) fn list_blobs(&self) -> Result<Vec<String>, BackupError> {
+        Ok(self.backend.list(ObjectKind::Blob)?)
+    }
+
+    pub(crate) fn delete_blob(&self, vault_path: &Path) -> Result<(), BackupError> {
+        Ok(self.backend.delete(ObjectKind::Blob, &blob_name(vault_path))?)
+    }
+
+    /// Checks that a blob decrypts and that its content matches the hash it is named after.
+    pub(crate) fn verify_blob(&self, vault_path: &Path) -> Result<bool, BackupError> {
+        let buffer = match self.read_blob(vault_path) {
+            Ok(buffer) => buffer,
+            Err(BackupError::VaultDecryptionError) => return Ok(false),
+            Err(err) => return Err(err),
+        };
+
+        let hash = blake3::hash(&buffer).to_hex().to_string();
+
+        Ok(hash[0..16] == blob_name(vault_path))
     }
 
-    fn vault_copy_file(&mut self, file: &mut VaultFile) -> Result<(), BackupError> {
+    fn vault_copy_file(&mut self, file: &mut VaultFile, options: &BackupOptions) -> Result<(), BackupError> {
         // println!("File name {}\nFile hash {}\nFile size {}\n\n", file.file_name, file.file_hash, file.file_size);
 
         // // let hash = &file.file_hash;
@@ -214,48 +608,52 @@ impl BackupVault {
             let mut hasher = blake3::Hasher::new();
             let hash = hasher.update(&buffer[..read_result]).finalize().to_hex().to_string();
 
-            let write_file_path = self.vault_path.join(hash[0..16].to_string());
+            let blob_path = PathBuf::from(hash[0..16].to_string());
 
-            let vault_file = File::create(&write_file_path);
+            // Identical content is already stored, no need to upload it again
+            if !self.has_blob(&blob_path)? {
+                let write_result = self.write_blob(&blob_path, &buffer[..read_result], &file.file_name, options.compression);
 
-            if vault_file.is_err() {
-                println!("Failed to create file: {}", write_file_path.to_str().unwrap());
-                return Err(BackupError::VaultFileCopyError);
+                if write_result.is_err() {
+                    println!("Failed to write file: {}", blob_path.display());
+                    return Err(BackupError::VaultFileCopyError);
+                }
             }
 
-            let mut vault_file = vault_file.unwrap();
+            // println!("Copied file: {}", blob_path.display());
 
-            let buffer = self.crypto.encrypt(&buffer[..read_result]);
-            let write_result = vault_file.write_all(&buffer);
-
-            if write_result.is_err() {
-                println!("Failed to write file: {}", write_file_path.to_str().unwrap());
-                return Err(BackupError::VaultFileCopyError);
-            }
-
-            // println!("Copied file: {}", write_file_path.to_str().unwrap());
-
-            file.vault_paths.push(write_file_path);
+            file.vault_paths.push(blob_path);
         }
 
-        return Ok(());
+        Ok(())
     }
 
-    fn vault_add_file(&mut self, file_path: &PathBuf) -> Option<VaultFile> {
+    /// Files that can't be opened or read, e.g. because they were removed during the walk, are
+    /// skipped with a message. Failing to store a file in the vault aborts the backup.
+    fn vault_add_file(&mut self, file_path: &PathBuf, options: &BackupOptions) -> Result<Option<VaultFile>, BackupError> {
         
-        let file = File::open(&file_path);
+        let file = File::open(file_path);
 
         if file.is_err() {
-            println!("Failed to open file: {}", file_path.to_str().unwrap());
-            return None;
+            println!("Failed to open file: {}", file_path.display());
+            return Ok(None);
         } 
         
-        let file_name = file_path.file_name().unwrap().to_str().unwrap().to_string();
-        let file_size = file_path.metadata().unwrap().len();
+        let file_name = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+
+        let metadata = match file_path.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                println!("Failed to read file: {}", file_path.display());
+                return Ok(None);
+            }
+        };
+
+        let file_size = metadata.len();
         
         let mut file = file.unwrap();
 
-        let mut file_buf = [0; BUF_SIZE];
+        let mut file_buf = vec![0; BUF_SIZE];
 
         let mut file_hasher = blake3::Hasher::new();
 
@@ -263,8 +661,8 @@ impl BackupVault {
             let read_result = file.read(&mut file_buf);
 
             if read_result.is_err() {
-                println!("Failed to read file: {}", file_path.to_str().unwrap());
-                break;
+                println!("Failed to read file: {}", file_path.display());
+                return Ok(None);
             }
 
             let read_result = read_result.unwrap();
@@ -285,31 +683,39 @@ impl BackupVault {
             file_path: file_path.clone(),
             file_size,
             vault_paths: vec![],
+            file_modified: file_modified(&metadata),
+            file_mode: file_mode(&metadata),
         };
 
 
         if self.files.is_empty() || !self.files.par_iter().any(|f| f.file_hash == vault_file.file_hash) {
-            self.vault_copy_file(&mut vault_file).expect("Failed to copy file")
+            self.vault_copy_file(&mut vault_file, options)?;
         }
         
-        return Some(vault_file);
+        Ok(Some(vault_file))
     }
 
-    pub fn backup(&mut self, files_path: &Vec<PathBuf>) -> Result<(), BackupError>{
+    /// Returns the id of the new snapshot.
+    #[allow(clippy::ptr_arg)]
+    pub fn backup(&mut self, files_path: &Vec<PathBuf>, options: &BackupOptions) -> Result<String, BackupError>{
         println!("Creating backup...");
 
-        let snapshot_id = Uuid::new_v4();
-        let sys_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.lock()?;
+        let result = self.backup_locked(files_path, options);
+        self.unlock();
 
-        let mut snapshot = Snapshot {
-            snapshot_id: snapshot_id.to_string(),
-            snapshot_time: sys_time.to_string(),
-            snapshot_files: vec![],
-        };
+        result
+    }
+
+    fn backup_locked(&mut self, files_path: &[PathBuf], options: &BackupOptions) -> Result<String, BackupError>{
 
-        let files_path = files_path.par_iter().flat_map(expand_file_path).collect::<Vec<PathBuf>>();
+        let mut snapshot = new_snapshot(files_path.iter().map(|path| fs::canonicalize(path).unwrap_or(path.clone())).collect(), options);
+        let snapshot_id = snapshot.snapshot_id.clone();
 
-        let vault_files: Vec<Option<VaultFile>> = files_path.iter().map(|file_path| self.vault_add_file(file_path)).collect();
+        // Walking the absolute paths stores absolute file paths, which can be compared with the live file system later
+        let files_path = snapshot.paths.par_iter().flat_map(|file_path| options.filter.walk(file_path)).collect::<Vec<PathBuf>>();
+
+        let vault_files: Vec<Option<VaultFile>> = files_path.iter().map(|file_path| self.vault_add_file(file_path, options)).collect::<Result<_, _>>()?;
         // let vault_files: Vec<VaultFile> = vault_files.into_iter().filter_map(|file| file).collect();
         let vault_files: Vec<VaultFile> = vault_files.into_par_iter().filter_map(|file| file).collect();
 
@@ -318,80 +724,42 @@ impl BackupVault {
         }
 
         self.snapshots.push(snapshot);
-        println!("Snapshot created {}", snapshot_id);
-
-        let vault_file = File::create(self.vault_path.join("vault.json"));
-
-        if vault_file.is_err() {
-            println!("Failed to create vault file");
-            return Err(BackupError::VaultFileOpenError);
-        }
+        self.save_index()?;
 
-        let mut vault_file = vault_file.unwrap();
-
-        let temp_password = self.password.clone();
-        self.password = "".to_string();
-        let vault_json_data = serde_json::to_string(&self);
-        self.password = temp_password;
-
-        if vault_json_data.is_err() {
-            println!("Failed to serialize vault data");
-            return Err(BackupError::VaultFileOpenError);
-        }
-
-        let vault_json_data = vault_json_data.unwrap();
-
-        let write_result = vault_file.write_all(vault_json_data.as_bytes());
-
-        if write_result.is_err() {
-            println!("Failed to write vault data");
-            return Err(BackupError::VaultFileOpenError);
-        }
+        println!("Snapshot created {}", snapshot_id);
 
-        Ok(())
+        Ok(snapshot_id)
     }
 
-    pub fn restore(&self, vault: &PathBuf, snapshot: &Option<String>, target: &PathBuf) {
-        println!("Restoring backup...");
-    
-        let vault = BackupVault::open(vault, &self.password).expect("Failed to open vault");
-
-        let snapshot = match snapshot {
-            // Some(snapshot) => vault.snapshots.iter().find(|s| s.snapshot_id == snapshot.clone()).expect("Snapshot not found"),
-            Some(snapshot) => vault.snapshots.par_iter().find_any(|s| s.snapshot_id == snapshot.clone()).expect("Snapshot not found"),
-            None => vault.snapshots.last().expect("No snapshots found"),
-        };
+    pub fn list_snapshots(&self, filter: &SnapshotFilter) {
+        println!("Snapshots list: ");
 
-        fs::create_dir_all(target).expect("Failed to create target directory");
+        for snapshot in self.snapshots.iter().filter(|snapshot| filter.matches(snapshot)) {
+            let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(snapshot.snapshot_time.parse::<u64>().unwrap());
+            let time = DateTime::<Utc>::from(time);
+            let id = snapshot.snapshot_id.clone();
+            println!("- {time}: {id}");
 
-        for vault_file in &snapshot.snapshot_files {
-            let file_path = target.join(&vault_file.file_name);
-            
-            if file_path.parent().is_some() {
-                fs::create_dir_all(&file_path.parent().unwrap()).expect("Failed to create directory");
+            if !snapshot.hostname.is_empty() {
+                println!("    host: {}", snapshot.hostname);
             }
 
-            let mut file = File::create(&file_path).expect("Failed to create file");
-
-            for vault_path in &vault_file.vault_paths {
-                let mut vault_file = File::open(vault_path).expect("Failed to open vault file");
-                let mut buffer = Vec::new();
-                vault_file.read_to_end(&mut buffer).expect("Failed to read vault file");
-                let buffer = vault.crypto.decrypt(&buffer).expect("Failed to decrypt file");
-                file.write_all(&buffer).expect("Failed to write file");
+            if !snapshot.username.is_empty() {
+                println!("    user: {}", snapshot.username);
             }
-        }
 
-    }
+            if !snapshot.paths.is_empty() {
+                let paths: Vec<String> = snapshot.paths.iter().map(|path| path.display().to_string()).collect();
+                println!("    paths: {}", paths.join(", "));
+            }
 
-    pub fn list_snapshots(&self) {
-        println!("Snapshots list: ");
+            if !snapshot.tags.is_empty() {
+                println!("    tags: {}", snapshot.tags.join(", "));
+            }
 
-        for snapshot in &self.snapshots {
-            let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(snapshot.snapshot_time.parse::<u64>().unwrap());
-            let time = DateTime::<Utc>::from(time);
-            let id = snapshot.snapshot_id.clone();
-            println!("- {time}: {id}");
+            if let Some(description) = &snapshot.description {
+                println!("    description: {}", description);
+            }
         }
     }
 
@@ -412,46 +780,268 @@ impl BackupVault {
         }
     }
 
-    pub fn delete_snapshot(&mut self, snapshot_id: &String) {
+    pub fn delete_snapshot(&mut self, snapshot_id: &String) -> Result<(), BackupError> {
         println!("Deleting snapshot...");
 
+        self.lock()?;
+        let result = self.delete_snapshot_locked(snapshot_id);
+        self.unlock();
+
+        result
+    }
+
+    fn delete_snapshot_locked(&mut self, snapshot_id: &String) -> Result<(), BackupError> {
         let snapshot_index = self.snapshots.iter().position(|s| s.snapshot_id == *snapshot_id);
 
         if snapshot_index.is_none() {
             println!("Snapshot not found");
-            return;
+            return Ok(());
         }
 
         let snapshot_index = snapshot_index.unwrap();
 
-        self.snapshots.remove(snapshot_index);
+        let removed = self.snapshots.remove(snapshot_index);
+        let result = self.save_index();
+
+        if result.is_err() {
+            self.snapshots.insert(snapshot_index, removed);
+        }
+
+        result
     }
 
 }
 
-fn is_directory_empty(path: &PathBuf) -> bool {
-    match fs::read_dir(path) {
-        Ok(mut dir) => dir.next().is_none(),
-        Err(_) => true
+/// The blake3 hash of a file's content, as stored in `VaultFile::file_hash`.
+pub(crate) fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            read => {
+                hasher.update(&buffer[..read]);
+            }
+        }
     }
+
+    Ok(hasher.finalize().to_hex().to_string())
 }
 
-fn expand_file_path(file_path: &PathBuf) -> Vec<PathBuf> {
-    let mut result = vec![];
-
-    if file_path.is_dir() {
-        for entry in fs::read_dir(file_path).unwrap() {
-            let entry = entry.unwrap();
-            let path = entry.path();
-            if path.is_dir() {
-                result.extend(expand_file_path(&path));
-            } else if path.is_file() {
-                result.push(path);
-            }
+pub(crate) fn file_modified(metadata: &fs::Metadata) -> Option<u64> {
+    metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok().map(|modified| modified.as_secs())
+}
+
+#[cfg(unix)]
+pub(crate) fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// An empty snapshot taken now of `paths`, with the metadata of this host and the options.
+pub(crate) fn new_snapshot(paths: Vec<PathBuf>, options: &BackupOptions) -> Snapshot {
+    Snapshot {
+        snapshot_id: Uuid::new_v4().to_string(),
+        snapshot_time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs().to_string(),
+        snapshot_files: vec![],
+        hostname: sysinfo::System::host_name().unwrap_or_default(),
+        username: current_username(),
+        paths,
+        program_version: env!("CARGO_PKG_VERSION").to_string(),
+        description: options.description.clone(),
+        tags: normalize_tags(&options.tags),
+    }
+}
+
+/// The features of vaults written in the current format.
+pub(crate) fn current_features() -> Vec<String> {
+    SUPPORTED_FEATURES.iter().map(|feature| feature.to_string()).collect()
+}
+
+/// Wraps the vault key for the password and/or keyfile that `key_mode` needs.
+pub(crate) fn new_key_slot(crypto: &CryptoModule, key_mode: KeyMode, key: &VaultKey, kdf: &KdfParams) -> Result<KeySlot, BackupError> {
+    let (keyfile, password) = match (key_mode, key.keyfile.as_ref(), key.password.as_ref()) {
+        (KeyMode::Password, _, Some(password)) => (None, Some(password)),
+        (KeyMode::Keyfile, Some(keyfile), _) => (Some(keyfile), None),
+        (KeyMode::KeyfileAndPassword, Some(keyfile), Some(password)) => (Some(keyfile), Some(password)),
+        (KeyMode::Password | KeyMode::KeyfileAndPassword, _, None) => return Err(BackupError::VaultPasswordRequired),
+        _ => return Err(BackupError::VaultKeyfileRequired),
+    };
+
+    let wrapped_key = match crypto.wrap_key(keyfile, password.map(|password| password.as_bytes()), kdf) {
+        Ok(wrapped_key) => wrapped_key,
+        Err(_) => {
+            println!("Failed to derive the key from the password, the KDF memory limit may be too high");
+            return Err(BackupError::VaultCreationError);
+        }
+    };
+
+    Ok(KeySlot {
+        key_mode,
+        kdf: password.map(|_| *kdf),
+        wrapped_key: STANDARD.encode(wrapped_key),
+    })
+}
+
+/// Tries the key slots the password and keyfile fit, then the keyfile as an exported vault key.
+fn open_key_slots(config: &VaultConfig, key: &VaultKey, params: &CryptoParams) -> Result<CryptoModule, BackupError> {
+    let password = key.password.as_ref().map(|password| password.as_bytes());
+
+    for slot in &config.key_slots {
+        let (keyfile, password) = match (slot.key_mode, key.keyfile.as_ref(), password) {
+            (KeyMode::Password, _, Some(password)) => (None, Some(password)),
+            (KeyMode::Keyfile, Some(keyfile), _) => (Some(keyfile), None),
+            (KeyMode::KeyfileAndPassword, Some(keyfile), Some(password)) => (Some(keyfile), Some(password)),
+            _ => continue,
+        };
+
+        let wrapped_key = match STANDARD.decode(&slot.wrapped_key) {
+            Ok(wrapped_key) => wrapped_key,
+            Err(_) => return Err(BackupError::VaultReadError),
+        };
+
+        match CryptoModule::unwrap_key(&wrapped_key, keyfile, password, &slot.kdf.unwrap_or_default(), params.export()) {
+            Ok(crypto) => return Ok(crypto),
+            Err(CryptoError::KdfError(_)) => {
+                println!("Failed to derive the key from the password, the KDF parameters of the vault need more memory than available");
+                return Err(BackupError::VaultReadError);
+            },
+            Err(_) => {},
+        }
+    }
+
+    if let Some(keyfile) = &key.keyfile {
+        if config.key_check == Some(key_check(keyfile.key())) {
+            return Ok(CryptoModule::import_with_keyfile(keyfile, params.export()));
+        }
+    }
+
+    if key.password.is_none() && config.key_mode == KeyMode::KeyfileAndPassword {
+        return Err(BackupError::VaultPasswordRequired);
+    }
+
+    Err(BackupError::VaultWrongPassword)
+}
+
+/// Vaults created before key slots derive their key from the password directly and verify it
+/// with the bcrypt hash.
+fn open_legacy_key(config: &VaultConfig, key: &VaultKey, params: &CryptoParams) -> Result<CryptoModule, BackupError> {
+    let password = match &key.password {
+        Some(password) => password,
+        None => return Err(BackupError::VaultPasswordRequired),
+    };
+
+    match bcrypt::verify(password.as_bytes(), &config.password_hash) {
+        Ok(true) => Ok(CryptoModule::import(password.as_bytes(), params.export())),
+        Ok(false) => Err(BackupError::VaultWrongPassword),
+        Err(_) => Err(BackupError::VaultReadError),
+    }
+}
+
+fn read_index(backend: &dyn Backend) -> Result<VaultIndex, BackupError> {
+    let index_data = match backend.read(ObjectKind::Index, "") {
+        Ok(data) => data,
+        Err(_) => return Err(BackupError::VaultReadError),
+    };
+
+    match serde_json::from_slice(&index_data) {
+        Ok(index) => Ok(index),
+        Err(_) => Err(BackupError::VaultReadError),
+    }
+}
+
+fn read_config(backend: &dyn Backend) -> Result<VaultConfig, BackupError> {
+    let config_data = match backend.read(ObjectKind::Config, "") {
+        Ok(data) => data,
+        Err(BackendError::NotFound) => return Err(BackupError::VaultDoesNotExist),
+        Err(err) => return Err(err.into()),
+    };
+
+    match serde_json::from_slice(&config_data) {
+        Ok(config) => Ok(config),
+        Err(_) => Err(BackupError::VaultReadError),
+    }
+}
+
+/// Older vaults store the full path of a blob, only its name is relevant.
+pub(crate) fn blob_name(vault_path: &Path) -> String {
+    vault_path.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::ops::Deref;
+
+    use super::*;
+
+    /// A directory below the temp dir that is removed when dropped, also when a test panics.
+    pub(crate) struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(prefix: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("quicky_{}_{}", prefix, Uuid::new_v4()));
+            fs::create_dir_all(&path).unwrap();
+
+            TempDir(path)
+        }
+    }
+
+    impl Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
         }
-    } else if file_path.is_file() {
-        result.push(file_path.clone());
     }
 
-    result
-}
\ No newline at end of file
+    pub(crate) fn test_key() -> VaultKey {
+        VaultKey::from_password(Zeroizing::new("password12345".to_string()))
+    }
+
+    /// A new vault in the `vault` directory of a temp dir, opened with `test_key`.
+    pub(crate) fn temp_vault(prefix: &str) -> (TempDir, BackupVault) {
+        let root = TempDir::new(prefix);
+        let vault = BackupVault::create(&root.join("vault"), &test_key(), &KdfParams::default()).unwrap();
+
+        (root, vault)
+    }
+
+    #[test]
+    fn concurrent_clients() {
+        let (root, _) = temp_vault("backup_vault");
+        fs::create_dir_all(root.join("data")).unwrap();
+        fs::write(root.join("data").join("a.txt"), "a").unwrap();
+
+        let mut first = BackupVault::open(&root.join("vault"), &test_key()).unwrap();
+        let mut second = BackupVault::open(&root.join("vault"), &test_key()).unwrap();
+
+        let first_snapshot = first.backup(&vec![root.join("data")], &BackupOptions::default()).unwrap();
+        // Opened before the first backup, so this client only learns about it when locking
+        let second_snapshot = second.backup(&vec![root.join("data")], &BackupOptions::default()).unwrap();
+
+        let snapshots: Vec<String> = BackupVault::open(&root.join("vault"), &test_key()).unwrap()
+            .snapshots.iter().map(|snapshot| snapshot.snapshot_id.clone()).collect();
+        assert_eq!(snapshots, vec![first_snapshot, second_snapshot]);
+
+        first.lock().unwrap();
+        assert!(matches!(second.lock(), Err(BackupError::VaultLocked)));
+        first.unlock();
+
+        second.lock().unwrap();
+        second.unlock();
+        assert!(first.backend.list(ObjectKind::Lock).unwrap().is_empty());
+    }
+}
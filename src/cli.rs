@@ -1,172 +1,1191 @@
-use std::{fs, io::Write};
-use std::path::PathBuf;
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use clap::{CommandFactory, Parser, Subcommand};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use zeroize::Zeroizing;
 
 use crate::backup_vault::BackupVault;
 use crate::backup_vault::BackupError;
+use crate::backup_vault::BackupOptions;
+use crate::backup_vault::Snapshot;
+use crate::backup_vault::{VaultKey, VAULT_FORMAT_VERSION};
+use crate::compression::Compression;
+use crate::diff::{diff_live, diff_snapshots};
+use crate::dump::{find_file, ArchiveFormat, DumpError};
+use crate::filter::{parse_size, ExcludeOptions, FileFilter, FilterError, RestoreFilter};
+use crate::hooks::{HookContext, HookError, HookStage, Hooks};
+use crate::kdf::{self, KdfAlgorithm, KdfError, KdfParams, KdfProfile};
+use crate::keyfile::{Keyfile, KeyfileError};
+use crate::password::{prompt_password, PasswordError, PasswordSource};
+use crate::profile::{Config, Job, ProfileError};
+use crate::replication::select_snapshots;
+use crate::restore::{verify_restore, Overwrite, RestoreOptions, RestoreTarget};
+use crate::server::{self, ServerOptions};
+use crate::snapshot::{parse_time, SelectorError, SnapshotFilter, SnapshotSelector, TagChanges};
+use crate::stream::StreamSource;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
+    #[command(flatten)]
+    pub password: PasswordArgs,
+
+    #[command(flatten)]
+    pub kdf: KdfArgs,
+
+    /// The config file with the jobs of run and backup --profile, ~/.config/quicky_backup/config.toml by default
+    #[arg(long, global = true, value_name = "FILE", env = "QUICKY_CONFIG")]
+    pub config: Option<PathBuf>,
+
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
 
+#[derive(Args, Debug, Default, Clone)]
+pub struct PasswordArgs {
+    /// Read the vault password from the first line of this file
+    #[arg(long, global = true, value_name = "FILE", env = "QUICKY_PASSWORD_FILE")]
+    password_file: Option<PathBuf>,
+
+    /// Use the first line printed by this command as the vault password, e.g. "pass show backup"
+    #[arg(long, global = true, value_name = "COMMAND")]
+    password_command: Option<String>,
+
+    /// Unlock the vault with this keyfile, see the key command
+    #[arg(long, global = true, value_name = "FILE", env = "QUICKY_KEYFILE")]
+    keyfile: Option<PathBuf>,
+
+    /// Protect a vault created with --keyfile by a password as well
+    #[arg(long, global = true, requires = "keyfile")]
+    with_password: bool,
+}
+
+impl PasswordArgs {
+    fn to_source(&self) -> PasswordSource {
+        PasswordSource {
+            password_file: self.password_file.clone(),
+            password_command: self.password_command.clone(),
+        }
+    }
+}
+
+/// How a new vault derives its key from the password, stored in the vault so it can differ per vault.
+#[derive(Args, Debug)]
+pub struct KdfArgs {
+    /// Password KDF strength for new vaults: interactive, moderate or sensitive
+    #[arg(long, global = true, value_name = "PROFILE", value_parser = KdfProfile::parse)]
+    kdf_profile: Option<KdfProfile>,
+
+    /// Password KDF algorithm for new vaults: argon2id, argon2i or scrypt
+    #[arg(long, global = true, value_name = "ALGORITHM", value_parser = KdfAlgorithm::parse)]
+    kdf_algorithm: Option<KdfAlgorithm>,
+
+    /// KDF opslimit for new vaults, overrides the profile
+    #[arg(long, global = true, value_name = "N")]
+    kdf_opslimit: Option<u64>,
+
+    /// KDF memory for new vaults, e.g. 256M, overrides the profile
+    #[arg(long, global = true, value_name = "SIZE", value_parser = parse_size)]
+    kdf_memlimit: Option<u64>,
+}
+
+impl KdfArgs {
+    fn to_params(&self) -> KdfParams {
+        let algorithm = self.kdf_algorithm.unwrap_or(KdfAlgorithm::Argon2id13);
+
+        let mut params = match KdfParams::from_profile(algorithm, self.kdf_profile.unwrap_or(KdfProfile::Interactive)) {
+            Some(params) => params,
+            None => {
+                println!("There is no {:?} profile for {}", self.kdf_profile.unwrap(), algorithm.as_str());
+                std::process::exit(1);
+            }
+        };
+
+        if let Some(opslimit) = self.kdf_opslimit {
+            params.opslimit = opslimit;
+        }
+
+        if let Some(memlimit) = self.kdf_memlimit {
+            params.memlimit = memlimit;
+        }
+
+        if let Err(KdfError::InvalidParams(err)) = params.validate() {
+            println!("Invalid KDF parameters: {}", err);
+            std::process::exit(1);
+        }
+
+        params
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct DestinationPasswordArgs {
+    /// Read the destination vault password from the first line of this file
+    #[arg(long, value_name = "FILE")]
+    destination_password_file: Option<PathBuf>,
+
+    /// Use the first line printed by this command as the destination vault password
+    #[arg(long, value_name = "COMMAND")]
+    destination_password_command: Option<String>,
+
+    /// Unlock the destination vault with this keyfile
+    #[arg(long, value_name = "FILE")]
+    destination_keyfile: Option<PathBuf>,
+
+    /// Protect a destination vault created with --destination-keyfile by a password as well
+    #[arg(long, requires = "destination_keyfile")]
+    destination_with_password: bool,
+
+    /// Unlock the destination vault with the key of the source vault instead of asking for its password
+    #[arg(long, conflicts_with_all = ["destination_password_file", "destination_password_command", "destination_keyfile"])]
+    reuse_source_key: bool,
+}
+
+// Parsed once per run, boxing the larger variants wouldn't gain anything
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 pub enum Commands {
+    /// creates a new vault, protected by a password, a --keyfile or both
+    Init {
+        /// The location of the new vault
+        #[arg(short, long)]
+        vault: PathBuf,
+    },
     /// performs backup of target directory
     Backup {
         /// The target location where the backup will be stored
-        #[arg(short, long)]
-        target: PathBuf,
+        #[arg(short, long, required_unless_present = "profile")]
+        target: Option<PathBuf>,
+
+        /// Start from a job of the config file, the options given here override or add to it
+        ///
+        /// DIR/FILE replace the sources of the job, excludes and tags are added to its own. The retention
+        /// policy of the job is only applied by the run command.
+        #[arg(long, value_name = "JOB")]
+        profile: Option<String>,
+
+        /// Create the vault if it does not exist yet, like the init command
+        #[arg(long)]
+        init: bool,
 
         /// The directories and files that will be backed up
         #[arg(value_name = "DIR/FILE")]
-        files: Vec<PathBuf>
+        files: Vec<PathBuf>,
+
+        /// Compression applied before encryption: off, auto (skips compressed formats) or a zstd level [default: auto]
+        #[arg(long, value_parser = Compression::parse)]
+        compression: Option<Compression>,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs,
+
+        #[command(flatten)]
+        hooks: HookArgs,
+
+        /// Tag the snapshot, can be given multiple times
+        #[arg(long, value_name = "TAG")]
+        tag: Vec<String>,
+
+        /// A free-form description stored with the snapshot
+        #[arg(long)]
+        description: Option<String>,
+
+        /// Back up what is read from stdin as a single file instead of DIR/FILE
+        #[arg(long, conflicts_with_all = ["files", "stdin_from_command"])]
+        stdin: bool,
+
+        /// Back up the output of the command given after --, the snapshot fails if it exits with an error
+        #[arg(long, conflicts_with = "files", requires = "command")]
+        stdin_from_command: bool,
+
+        /// The file name the data of --stdin or --stdin-from-command is stored under [default: stdin]
+        #[arg(long, value_name = "NAME")]
+        stdin_filename: Option<PathBuf>,
+
+        /// The command run by --stdin-from-command and its arguments
+        #[arg(last = true, value_name = "COMMAND", requires = "stdin_from_command")]
+        command: Vec<String>,
+    },
+    /// runs a backup job of the config file, then deletes the snapshots its retention policy doesn't keep
+    Run {
+        /// The name of the job, its [jobs.NAME] table in the config file
+        job: String,
+    },
+    /// stores the files of a tar, tar.gz, tar.zst or zip archive as a new snapshot
+    ImportArchive {
+        /// The target location where the backup will be stored
+        #[arg(short, long)]
+        target: PathBuf,
+
+        /// Create the vault if it does not exist yet, like the init command
+        #[arg(long)]
+        init: bool,
+
+        /// The archive to import
+        #[arg(value_name = "ARCHIVE")]
+        archive: PathBuf,
+
+        /// The snapshot time, e.g. 2020-06-30 or 2020-06-30 18:00:00 in UTC, the newest member's otherwise
+        #[arg(long, value_parser = parse_time)]
+        time: Option<u64>,
+
+        /// Compression applied before encryption: off, auto (skips compressed formats) or a zstd level
+        #[arg(long, default_value = "auto", value_parser = Compression::parse)]
+        compression: Compression,
+
+        /// Tag the snapshot, can be given multiple times
+        #[arg(long, value_name = "TAG")]
+        tag: Vec<String>,
+
+        /// A free-form description stored with the snapshot
+        #[arg(long)]
+        description: Option<String>,
     },
-    /// performs recovery of target backup
+    /// performs recovery of target backup, exits with code 3 if some files could not be restored
     Restore {
         /// The target location where the backup is
         #[arg(short, long)]
         vault: PathBuf,
 
-        /// The id of the snapshot to be restored
-        #[arg(short, long)]
-        snapshot: Option<String>,
+        /// The snapshot to be restored: an id or unique id prefix, latest, latest:host=NAME,tag=TAG,
+        /// tag:TAG, host:NAME, path:PATH or before:DATE
+        #[arg(short, long, default_value = "latest", value_parser = SnapshotSelector::parse)]
+        snapshot: SnapshotSelector,
 
         /// The directory where the backup will be restored
+        #[arg(value_name = "DIR", required_unless_present = "in_place")]
+        target: Option<PathBuf>,
+
+        /// Only restore these files or directories, given as backed up or relative to DIR
+        #[arg(value_name = "PATH")]
+        paths: Vec<PathBuf>,
+
+        /// Restore files to the locations they were backed up from, all arguments are then paths to restore
+        #[arg(long)]
+        in_place: bool,
+
+        /// Don't ask for confirmation before restoring in place
+        #[arg(long, requires = "in_place")]
+        yes: bool,
+
+        /// What to do with existing files: always, never, if-newer or if-changed
+        #[arg(long, default_value = "always", value_parser = Overwrite::parse)]
+        overwrite: Overwrite,
+
+        /// Print what would be restored, overwritten and deleted without changing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Delete files below the restored paths that are not in the snapshot
+        #[arg(long)]
+        delete: bool,
+
+        /// Restore files with missing or damaged data anyway, with the damaged ranges zero-filled
+        #[arg(long)]
+        partial: bool,
+
+        /// Hash every restored file and compare it with the backup
+        #[arg(long)]
+        verify: bool,
+
+        /// Write the report of restored and failed files as json to this file
+        #[arg(long, value_name = "FILE")]
+        report: Option<PathBuf>,
+
+        /// Only restore files matching a gitignore style pattern, can be given multiple times
+        #[arg(long, value_name = "PATTERN")]
+        include: Vec<String>,
+
+        /// Don't restore files matching a gitignore style pattern, can be given multiple times
+        #[arg(short, long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+    },
+    /// checks that the files restored to a directory match the snapshot, exits with code 3 if some don't
+    VerifyRestore {
+        /// The target location where the backup is
+        #[arg(short, long)]
+        vault: PathBuf,
+
+        /// The snapshot that was restored, selected like for restore
+        #[arg(value_name = "SNAPSHOT", value_parser = SnapshotSelector::parse)]
+        snapshot: SnapshotSelector,
+
+        /// The directory the snapshot was restored to
         #[arg(value_name = "DIR")]
         target: PathBuf,
+
+        /// Only check these files or directories, like for restore
+        #[arg(value_name = "PATH")]
+        paths: Vec<PathBuf>,
+
+        /// Only check files matching a gitignore style pattern, like for restore
+        #[arg(long, value_name = "PATTERN")]
+        include: Vec<String>,
+
+        /// Don't check files matching a gitignore style pattern, like for restore
+        #[arg(short, long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+    },
+    /// writes a file of a snapshot to stdout, or with --archive a directory as tar or zip
+    #[command(visible_alias = "cat")]
+    Dump {
+        /// The target location where the backup is
+        #[arg(short, long)]
+        vault: PathBuf,
+
+        /// The snapshot to read from, selected like for restore
+        #[arg(value_name = "SNAPSHOT", value_parser = SnapshotSelector::parse)]
+        snapshot: SnapshotSelector,
+
+        /// The file as it was backed up or relative to a restore target, or with --archive a directory
+        #[arg(value_name = "PATH", required_unless_present = "archive")]
+        path: Option<PathBuf>,
+
+        /// Writes the files below PATH, or the whole snapshot, as an archive: tar, tar.zst or zip
+        #[arg(long, value_name = "FORMAT", value_parser = ArchiveFormat::parse)]
+        archive: Option<ArchiveFormat>,
+    },
+    /// writes a snapshot as a tar, tar.zst or zip archive that can be opened without quicky_backup
+    ///
+    /// Every format is streamed, files are written while their blobs are decrypted one at a time.
+    /// Zip archives use zip64 for files and archives of 4 GiB and more.
+    Export {
+        /// The target location where the backup is
+        #[arg(short, long)]
+        vault: PathBuf,
+
+        /// The snapshot to export, selected like for restore
+        #[arg(value_name = "SNAPSHOT", value_parser = SnapshotSelector::parse)]
+        snapshot: SnapshotSelector,
+
+        /// The archive to write, - for stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+
+        /// tar, tar.zst or zip, guessed from the extension of FILE if not given
+        #[arg(long, value_parser = ArchiveFormat::parse)]
+        format: Option<ArchiveFormat>,
+    },
+    /// mounts the snapshots read-only as a file system, needs a build with the mount feature
+    Mount {
+        /// The target location where the backup is
+        #[arg(short, long)]
+        vault: PathBuf,
+
+        /// An empty directory to mount the vault on
+        #[arg(value_name = "MOUNTPOINT")]
+        mountpoint: PathBuf,
     },
     ListSnapshots {
         /// The target location where the backup is
         #[arg(short, long)]
         vault: PathBuf,
+
+        #[command(flatten)]
+        filter: SnapshotFilterArgs,
     },
     ListSnapshotContents {
         /// The target location where the backup is
         #[arg(short, long)]
         vault: PathBuf,
 
-        /// The id of the snapshot to be listed
-        #[arg(short, long)]
-        snapshot: Option<String>,
+        /// The snapshot to be listed, selected like for restore
+        #[arg(short, long, default_value = "latest", value_parser = SnapshotSelector::parse)]
+        snapshot: SnapshotSelector,
     },
     DeleteSnapshot {
         /// The target location where the backup is
         #[arg(short, long)]
         vault: PathBuf,
 
-        /// The id of the snapshot to be deleted
+        /// The snapshot to be deleted, selected like for restore
+        #[arg(short, long, value_parser = SnapshotSelector::parse)]
+        snapshot: SnapshotSelector,
+    },
+    /// lists the files added, removed and modified between two snapshots, or a snapshot and the file system
+    Diff {
+        /// The target location where the backup is
+        #[arg(short, long)]
+        vault: PathBuf,
+
+        /// The older snapshot, selected like for restore
+        #[arg(value_name = "SNAPSHOT", value_parser = SnapshotSelector::parse)]
+        from: SnapshotSelector,
+
+        /// The newer snapshot, selected like for restore
+        #[arg(value_name = "SNAPSHOT", value_parser = SnapshotSelector::parse, required_unless_present = "live")]
+        to: Option<SnapshotSelector>,
+
+        /// Compare the snapshot with the files in this directory instead
+        #[arg(long, value_name = "DIR", conflicts_with = "to")]
+        live: Option<PathBuf>,
+
+        /// Print the changes as json
+        #[arg(long)]
+        json: bool,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs,
+    },
+    /// changes the tags of a snapshot
+    Tag {
+        /// The target location where the backup is
         #[arg(short, long)]
-        snapshot: String,
+        vault: PathBuf,
+
+        /// The snapshot to be tagged, selected like for restore
+        #[arg(short, long, value_parser = SnapshotSelector::parse)]
+        snapshot: SnapshotSelector,
+
+        /// Replace all tags, can be given multiple times, --set "" removes all tags
+        #[arg(long, value_name = "TAG")]
+        set: Option<Vec<String>>,
+
+        /// Add a tag, can be given multiple times
+        #[arg(long, value_name = "TAG")]
+        add: Vec<String>,
+
+        /// Remove a tag, can be given multiple times
+        #[arg(long, value_name = "TAG")]
+        remove: Vec<String>,
     },
-    
+    /// copies snapshots to another vault, transferring only the data missing there
+    Copy {
+        /// The vault the snapshots are copied from
+        #[arg(short, long)]
+        from: PathBuf,
+
+        /// The vault the snapshots are copied to
+        #[arg(short, long)]
+        to: PathBuf,
+
+        /// Create the destination vault if it does not exist yet
+        #[arg(long)]
+        init: bool,
+
+        /// The snapshots to be copied, selected like for restore, all snapshots if none are given
+        #[arg(short, long, value_parser = SnapshotSelector::parse)]
+        snapshot: Vec<SnapshotSelector>,
+
+        /// Only copy the N most recent snapshots
+        #[arg(long, value_name = "N")]
+        latest: Option<usize>,
+
+        #[command(flatten)]
+        destination_password: DestinationPasswordArgs,
+    },
+    /// makes a vault an exact replica of another one, including deletions
+    Mirror {
+        /// The vault that is mirrored
+        #[arg(short, long)]
+        from: PathBuf,
+
+        /// The vault that becomes the replica
+        #[arg(short, long)]
+        to: PathBuf,
+
+        /// Create the replica if it does not exist yet
+        #[arg(long)]
+        init: bool,
+
+        /// Decrypt and hash every blob of the replica instead of only checking that it exists
+        #[arg(long)]
+        verify_data: bool,
+
+        /// Write the report of what changed as json to this file
+        #[arg(long, value_name = "FILE")]
+        report: Option<PathBuf>,
+
+        #[command(flatten)]
+        destination_password: DestinationPasswordArgs,
+    },
+    /// turns append-only mode of a vault on or off, turning it off on a served vault requires admin credentials
+    AppendOnly {
+        /// The target location where the backup is
+        #[arg(short, long)]
+        vault: PathBuf,
+
+        #[arg(value_enum)]
+        mode: Toggle,
+    },
+    /// upgrades a vault written by an older version to the current format
+    Migrate {
+        /// The target location where the backup is
+        #[arg(short, long)]
+        vault: PathBuf,
+    },
+    /// removes stale locks left behind by interrupted backups
+    Unlock {
+        /// The target location where the backup is
+        #[arg(short, long)]
+        vault: PathBuf,
+    },
+    /// serves the vaults in a directory over HTTP, clients use them as `rest:http://host:port/<vault>`
+    Serve {
+        /// The directory containing the served vaults
+        #[arg(short, long)]
+        root: PathBuf,
+
+        /// The address the server listens on
+        #[arg(short, long, default_value = "127.0.0.1:8000")]
+        listen: String,
+
+        /// PEM certificate, enables TLS together with --tls-key
+        #[arg(long)]
+        tls_cert: Option<PathBuf>,
+
+        /// PEM private key, enables TLS together with --tls-cert
+        #[arg(long)]
+        tls_key: Option<PathBuf>,
+
+        /// File with `user:bcrypt-hash` lines, enables basic auth
+        #[arg(long)]
+        htpasswd: Option<PathBuf>,
+
+        /// File with `user:bcrypt-hash` lines for admins, who may delete data in append-only mode
+        #[arg(long)]
+        admin_htpasswd: Option<PathBuf>,
+
+        /// Refuse deleting or overwriting vault data for all vaults, not only the append-only ones
+        #[arg(long)]
+        append_only: bool,
+    },
+    /// measures the password KDF on this machine and suggests parameters that take about the target time
+    KdfBenchmark {
+        /// How long unlocking a vault may take, in milliseconds
+        #[arg(long, default_value = "1000", value_name = "MS")]
+        target_ms: u64,
+
+        /// The algorithm to be measured: argon2id, argon2i or scrypt
+        #[arg(long, default_value = "argon2id", value_parser = KdfAlgorithm::parse)]
+        algorithm: KdfAlgorithm,
+
+        /// Keep the memory fixed and only tune the opslimit, e.g. 256M
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        memlimit: Option<u64>,
+    },
+    /// creates keyfiles, which unlock vaults instead of or together with a password
+    Key {
+        #[command(subcommand)]
+        command: KeyCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeyCommands {
+    /// writes a new random 256-bit keyfile, vaults created with --keyfile use it as their key
+    Generate {
+        /// The keyfile to be written, it must not exist yet
+        #[arg(value_name = "FILE")]
+        output: PathBuf,
+    },
+    /// writes the key of a vault to a keyfile that unlocks the vault without its password
+    Export {
+        /// The target location where the backup is
+        #[arg(short, long)]
+        vault: PathBuf,
+
+        /// The keyfile to be written, it must not exist yet
+        #[arg(value_name = "FILE")]
+        output: PathBuf,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct HookArgs {
+    /// A shell command run before the backup, e.g. to quiesce a database, the backup is aborted if it fails
+    ///
+    /// Hooks get QUICKY_HOOK (pre, post or error), QUICKY_STATUS, QUICKY_VAULT, QUICKY_SNAPSHOT_ID,
+    /// QUICKY_FILES, QUICKY_BYTES, QUICKY_DURATION in seconds and QUICKY_ERROR in their environment.
+    #[arg(long, value_name = "COMMAND")]
+    pre_hook: Option<String>,
+
+    /// A shell command run after a successful backup
+    #[arg(long, value_name = "COMMAND")]
+    post_hook: Option<String>,
+
+    /// A shell command run when the pre hook, opening the vault, the backup or the post hook failed
+    #[arg(long, value_name = "COMMAND")]
+    on_error: Option<String>,
+
+    /// Back up even if the pre hook fails
+    #[arg(long)]
+    continue_on_pre_hook_failure: bool,
+}
+
+impl HookArgs {
+    fn to_hooks(&self) -> Hooks {
+        Hooks {
+            pre: self.pre_hook.clone(),
+            post: self.post_hook.clone(),
+            on_error: self.on_error.clone(),
+            continue_on_pre_failure: self.continue_on_pre_hook_failure,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ExcludeArgs {
+    /// Exclude files matching a gitignore style pattern, patterns without a leading `/` match at any depth
+    #[arg(short, long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Like --exclude, but case-insensitive
+    #[arg(long, value_name = "PATTERN")]
+    iexclude: Vec<String>,
+
+    /// Read exclude patterns from a file, one per line
+    #[arg(long, value_name = "FILE")]
+    exclude_file: Vec<PathBuf>,
+
+    /// Back up files matching this pattern even if they are excluded
+    #[arg(long, value_name = "PATTERN")]
+    include: Vec<String>,
+
+    /// Skip the contents of directories containing a CACHEDIR.TAG file
+    #[arg(long)]
+    exclude_caches: bool,
+
+    /// Skip files larger than this size, e.g. 500K, 10M or 1G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    exclude_larger_than: Option<u64>,
+
+    /// Don't cross file system boundaries below the backed up paths
+    #[arg(short = 'x', long)]
+    one_file_system: bool,
+}
+
+impl ExcludeArgs {
+    fn to_options(&self) -> ExcludeOptions {
+        ExcludeOptions {
+            excludes: self.exclude.clone(),
+            iexcludes: self.iexclude.clone(),
+            exclude_files: self.exclude_file.clone(),
+            includes: self.include.clone(),
+            exclude_caches: self.exclude_caches,
+            exclude_larger_than: self.exclude_larger_than,
+            one_file_system: self.one_file_system,
+        }
+    }
+
+    fn to_filter(&self) -> FileFilter {
+        file_filter(self.to_options())
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct SnapshotFilterArgs {
+    /// Only snapshots taken on this host, can be given multiple times
+    #[arg(long, value_name = "HOSTNAME")]
+    host: Vec<String>,
+
+    /// Only snapshots with this tag, can be given multiple times to require all of them
+    #[arg(long, value_name = "TAG")]
+    tag: Vec<String>,
+
+    /// Only snapshots of this backed up path, can be given multiple times to require all of them
+    #[arg(long, value_name = "PATH")]
+    path: Vec<PathBuf>,
+}
+
+impl SnapshotFilterArgs {
+    fn to_filter(&self) -> SnapshotFilter {
+        SnapshotFilter {
+            hosts: self.host.clone(),
+            tags: self.tag.clone(),
+            paths: self.path.clone(),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Toggle {
+    On,
+    Off,
 }
 
 impl Cli {
     pub fn execute(&self) {
         match &self.command {
-            Some(Commands::Backup { target, files }) => {
-                let password = ask_for_password();
+            Some(Commands::Init { vault }) => {
+                let key = self.ask_for_key(vault);
 
-                let mut backup_vault = match BackupVault::open(target, &password) {
-                    Ok(vault) => vault,
-                    Err(BackupError::VaultWrongPassword) => {
-                        println!("Wrong password");
-                        std::process::exit(1);
+                create_vault(vault, &key, &self.password.to_source(), &self.kdf.to_params());
+
+                println!("Created vault {}", vault.display());
+            },
+            Some(Commands::Backup { target, profile, init, files, compression, exclude, hooks, tag, description, stdin, stdin_from_command, stdin_filename, command }) => {
+                let plan = BackupPlan {
+                    target: target.clone(),
+                    init: *init,
+                    files: files.clone(),
+                    source: match (stdin, stdin_from_command) {
+                        (true, _) => Some(StreamSource::Stdin),
+                        (_, true) => Some(StreamSource::Command(command.clone())),
+                        _ => None,
                     },
-                    Err(BackupError::VaultDoesNotExist) => match BackupVault::create(target, &password) {
-                        Ok(vault) => vault,
+                    stdin_filename: stdin_filename.clone(),
+                    compression: *compression,
+                    exclude: exclude.to_options(),
+                    hooks: hooks.to_hooks(),
+                    tags: tag.clone(),
+                    description: description.clone(),
+                    password: self.password.clone(),
+                };
+
+                let plan = match profile {
+                    Some(profile) => plan.or_job(&self.load_job(profile)),
+                    None => plan,
+                };
+
+                self.backup(&plan);
+            },
+            Some(Commands::Run { job: name }) => {
+                let job = self.load_job(name);
+                let plan = BackupPlan {
+                    password: self.password.clone(),
+                    ..Default::default()
+                };
+
+                let mut backup_vault = self.backup(&plan.or_job(&job));
+
+                if let Some(retention) = &job.retention {
+                    match backup_vault.apply_retention(retention, &job.snapshot_filter()) {
+                        Ok(deleted) if deleted.is_empty() => println!("The retention policy keeps all snapshots of {}", name),
+                        Ok(deleted) => {
+                            for snapshot_id in &deleted {
+                                println!("Deleted snapshot {}", snapshot_id);
+                            }
+
+                            println!("Deleted {} snapshots the retention policy doesn't keep", deleted.len());
+                        },
+                        Err(BackupError::VaultAccessDenied) => {
+                            println!("The vault is append-only, applying the retention policy requires admin credentials or local access to the storage host");
+                            std::process::exit(1);
+                        },
                         Err(_) => {
-                            println!("Failed to create vault");
+                            println!("Failed to apply the retention policy");
                             std::process::exit(1);
                         }
-                    },
-                    Err(err) => {
-                        println!("Failed to open vault");
-                        dbg!(err);
-                        std::process::exit(1);
                     }
+                }
+            },
+            Some(Commands::ImportArchive { target, init, archive, time, compression, tag, description }) => {
+                if !archive.is_file() {
+                    println!("{} is not a file", archive.display());
+                    std::process::exit(1);
+                }
+
+                let key = self.ask_for_key(target);
+
+                let mut backup_vault = open_or_create_vault(target, &key, &self.password.to_source(), &self.kdf, *init);
+
+                let options = BackupOptions {
+                    compression: *compression,
+                    tags: tag.clone(),
+                    description: description.clone(),
+                    ..Default::default()
                 };
 
-                backup_vault.backup(files).expect("backup-vault failed to backup files");
+                if backup_vault.import_archive(archive, *time, &options).is_err() {
+                    println!("Failed to import {}", archive.display());
+                    std::process::exit(1);
+                }
             },
-            Some(Commands::Restore { vault, target, snapshot }) => {
-                let password = ask_for_password();
+            Some(Commands::Restore { vault, target, snapshot, paths, include, exclude, in_place, yes, overwrite, dry_run, delete, partial, verify, report }) => {
+                let (target, paths) = match (target, in_place) {
+                    (Some(target), false) => (RestoreTarget::Directory(target.clone()), paths.clone()),
+                    (target, _) => (RestoreTarget::OriginalLocation, target.iter().chain(paths).cloned().collect()),
+                };
 
-                let backup_vault = match BackupVault::open(vault, &password) {
-                    Ok(vault) => vault,
-                    Err(BackupError::VaultWrongPassword) => {
-                        println!("Wrong password");
-                        std::process::exit(1);
-                    },
+                let options = RestoreOptions {
+                    filter: restore_filter(paths, include, exclude),
+                    overwrite: *overwrite,
+                    dry_run: *dry_run,
+                    delete: *delete,
+                    partial: *partial,
+                    verify: *verify,
+                };
+
+                let key = self.ask_for_key(vault);
+
+                let backup_vault = open_vault(vault, &key);
+                let snapshot_id = select_snapshot(&backup_vault, snapshot).snapshot_id.clone();
+
+                if *in_place && !*dry_run && !*yes && !confirm("Restore to the original locations, replacing existing files as --overwrite says?") {
+                    println!("Restore cancelled");
+                    std::process::exit(1);
+                }
+
+                let restore_report = match backup_vault.restore(&snapshot_id, &target, &options) {
+                    Ok(restore_report) => restore_report,
                     Err(_) => {
-                        println!("Failed to open vault");
+                        println!("Failed to restore snapshot");
+                        std::process::exit(1);
+                    }
+                };
+
+                if let Some(report) = report {
+                    if fs::write(report, serde_json::to_string_pretty(&restore_report).unwrap()).is_err() {
+                        println!("Failed to write report {}", report.display());
                         std::process::exit(1);
                     }
+                }
+
+                if !restore_report.failed.is_empty() {
+                    std::process::exit(3);
+                }
+            },
+            Some(Commands::VerifyRestore { vault, snapshot, target, paths, include, exclude }) => {
+                let filter = restore_filter(paths.clone(), include, exclude);
+                let key = self.ask_for_key(vault);
+
+                let backup_vault = open_vault(vault, &key);
+                let snapshot = select_snapshot(&backup_vault, snapshot);
+
+                let verify_report = verify_restore(snapshot, &RestoreTarget::Directory(target.clone()), &filter);
+
+                println!("Verified {} files, {} failed", verify_report.verified, verify_report.failed.len());
+
+                if !verify_report.failed.is_empty() {
+                    std::process::exit(3);
+                }
+            },
+            Some(Commands::Dump { vault, snapshot, path, archive }) => {
+                if archive.is_some() && std::io::stdout().is_terminal() {
+                    eprintln!("Refusing to write an archive to a terminal, redirect the output to a file");
+                    std::process::exit(1);
+                }
+
+                let key = self.ask_for_key(vault);
+
+                let backup_vault = open_vault(vault, &key);
+                let snapshot = select_snapshot(&backup_vault, snapshot);
+                let mut stdout = std::io::stdout().lock();
+
+                let result = match (archive, path) {
+                    (Some(format), path) => backup_vault.dump_archive(snapshot, path.as_deref(), *format, stdout).map(|_| ()),
+                    (None, Some(path)) => find_file(snapshot, path).and_then(|vault_file| backup_vault.dump_file(vault_file, &mut stdout)),
+                    (None, None) => unreachable!("clap requires a path or --archive"),
+                };
+
+                exit_on_dump_error(result, snapshot);
+            },
+            Some(Commands::Export { vault, snapshot, output, format }) => {
+                let to_stdout = output.as_os_str() == "-";
+
+                if to_stdout && std::io::stdout().is_terminal() {
+                    eprintln!("Refusing to write an archive to a terminal, redirect the output to a file");
+                    std::process::exit(1);
+                }
+
+                let format = format.unwrap_or(ArchiveFormat::from_path(output));
+                let key = self.ask_for_key(vault);
+
+                let backup_vault = open_vault(vault, &key);
+                let snapshot = select_snapshot(&backup_vault, snapshot);
+
+                if to_stdout {
+                    let result = backup_vault.dump_archive(snapshot, None, format, std::io::stdout().lock());
+                    exit_on_dump_error(result.map(|_| ()), snapshot);
+                } else {
+                    let file = match fs::File::create(output) {
+                        Ok(file) => file,
+                        Err(err) => {
+                            println!("Failed to create {}: {}", output.display(), err);
+                            std::process::exit(1);
+                        }
+                    };
+
+                    match backup_vault.dump_archive(snapshot, None, format, std::io::BufWriter::new(file)) {
+                        Ok(files) => println!("Exported {} files of snapshot {} to {}", files, snapshot.snapshot_id, output.display()),
+                        Err(err) => {
+                            // Don't leave a truncated archive behind
+                            let _ = fs::remove_file(output);
+                            exit_on_dump_error(Err(err), snapshot);
+                        }
+                    }
+                }
+            },
+            #[cfg(feature = "mount")]
+            Some(Commands::Mount { vault, mountpoint }) => {
+                if !mountpoint.is_dir() {
+                    println!("{} is not a directory", mountpoint.display());
+                    std::process::exit(1);
+                }
+
+                let key = self.ask_for_key(vault);
+
+                let backup_vault = open_vault(vault, &key);
+
+                println!("Mounting {} on {}, unmount with: fusermount -u {}", vault.display(), mountpoint.display(), mountpoint.display());
+
+                if let Err(err) = crate::mount::mount(backup_vault, mountpoint) {
+                    println!("Failed to mount: {}", err);
+                    std::process::exit(1);
+                }
+            },
+            #[cfg(not(feature = "mount"))]
+            Some(Commands::Mount { .. }) => {
+                println!("This build has no FUSE support, build it with: cargo build --features mount");
+                std::process::exit(1);
+            },
+            Some(Commands::ListSnapshots { vault, filter }) => {
+                let key = self.ask_for_key(vault);
+
+                let backup_vault = open_vault(vault, &key);
+
+                backup_vault.list_snapshots(&filter.to_filter());
+            },
+            Some(Commands::Diff { vault, from, to, live, json, exclude }) => {
+                let filter = exclude.to_filter();
+                let key = self.ask_for_key(vault);
+
+                let backup_vault = open_vault(vault, &key);
+                let from = select_snapshot(&backup_vault, from);
+
+                let report = match (to, live) {
+                    (_, Some(live)) if !live.is_dir() => {
+                        println!("{} is not a directory", live.display());
+                        std::process::exit(1);
+                    },
+                    (_, Some(live)) => diff_live(from, live, &filter),
+                    (Some(to), None) => diff_snapshots(from, select_snapshot(&backup_vault, to)),
+                    (None, None) => unreachable!("clap requires a second snapshot or --live"),
                 };
 
-                backup_vault.restore(vault, snapshot, target);
+                if *json {
+                    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                } else {
+                    report.print();
+                }
             },
-            Some(Commands::ListSnapshots { vault }) => {
-                let password = ask_for_password();
+            Some(Commands::Tag { vault, snapshot, set, add, remove }) => {
+                let changes = TagChanges {
+                    set: set.clone(),
+                    add: add.clone(),
+                    remove: remove.clone(),
+                };
+
+                if changes.is_empty() {
+                    println!("Nothing to change, pass --set, --add or --remove");
+                    std::process::exit(1);
+                }
+
+                let key = self.ask_for_key(vault);
 
-                let backup_vault = match BackupVault::open(vault, &password) {
-                    Ok(vault) => vault,
-                    Err(BackupError::VaultWrongPassword) => {
-                        println!("Wrong password");
+                let mut backup_vault = open_vault(vault, &key);
+                let snapshot_id = select_snapshot(&backup_vault, snapshot).snapshot_id.clone();
+
+                match backup_vault.tag_snapshot(&snapshot_id, &changes) {
+                    Ok(tags) if tags.is_empty() => println!("Snapshot {} has no tags", snapshot_id),
+                    Ok(tags) => println!("Snapshot {} tags: {}", snapshot_id, tags.join(", ")),
+                    Err(BackupError::VaultAccessDenied) => {
+                        println!("The vault is append-only, changing tags requires admin credentials or local access to the storage host");
                         std::process::exit(1);
                     },
                     Err(_) => {
-                        println!("Failed to open vault");
+                        println!("Failed to change tags");
                         std::process::exit(1);
                     }
-                };
-
-                backup_vault.list_snapshots();
+                }
             },
             Some(Commands::DeleteSnapshot { vault, snapshot }) => {
-                let password = ask_for_password();
+                let key = self.ask_for_key(vault);
 
-                let mut backup_vault = match BackupVault::open(vault, &password) {
-                    Ok(vault) => vault,
-                    Err(BackupError::VaultWrongPassword) => {
-                        println!("Wrong password");
+                let mut backup_vault = open_vault(vault, &key);
+                let snapshot_id = select_snapshot(&backup_vault, snapshot).snapshot_id.clone();
+
+                match backup_vault.delete_snapshot(&snapshot_id) {
+                    Ok(_) => {},
+                    Err(BackupError::VaultAccessDenied) => {
+                        println!("The vault is append-only, deleting requires admin credentials or local access to the storage host");
                         std::process::exit(1);
                     },
                     Err(_) => {
-                        println!("Failed to open vault");
+                        println!("Failed to delete snapshot");
                         std::process::exit(1);
                     }
-                };
+                }
+            },
+            Some(Commands::AppendOnly { vault, mode }) => {
+                let key = self.ask_for_key(vault);
 
-                backup_vault.delete_snapshot(snapshot);
+                let mut backup_vault = open_vault(vault, &key);
+
+                match backup_vault.set_append_only(matches!(mode, Toggle::On)) {
+                    Ok(_) => println!("Append-only mode {:?}", mode),
+                    Err(BackupError::VaultAccessDenied) => {
+                        println!("Changing append-only mode of a served vault requires admin credentials");
+                        std::process::exit(1);
+                    },
+                    Err(_) => {
+                        println!("Failed to change append-only mode");
+                        std::process::exit(1);
+                    }
+                }
             },
-            Some(Commands::ListSnapshotContents { vault, snapshot }) => {
-                let password = ask_for_password();
+            Some(Commands::Migrate { vault }) => {
+                let key = self.ask_for_key(vault);
 
-                let backup_vault = match BackupVault::open(vault, &password) {
-                    Ok(vault) => vault,
-                    Err(BackupError::VaultWrongPassword) => {
-                        println!("Wrong password");
+                let mut backup_vault = open_vault(vault, &key);
+
+                match backup_vault.migrate(&key, &self.kdf.to_params()) {
+                    Ok(report) if report.from_version == VAULT_FORMAT_VERSION => {
+                        println!("The vault already has format version {}", VAULT_FORMAT_VERSION);
+                    },
+                    Ok(report) => {
+                        println!("Migrated the vault from format version {} to {}", report.from_version, VAULT_FORMAT_VERSION);
+                        println!("Blobs sealed again: {}", report.blobs_rewritten);
+                    },
+                    Err(BackupError::VaultAccessDenied) => {
+                        println!("Migrating a served append-only vault requires admin credentials");
                         std::process::exit(1);
                     },
+                    Err(err) => {
+                        println!("Failed to migrate vault: {}", err);
+                        std::process::exit(1);
+                    }
+                }
+            },
+            Some(Commands::Unlock { vault }) => {
+                let key = self.ask_for_key(vault);
+
+                let backup_vault = open_vault(vault, &key);
+
+                if backup_vault.remove_locks().is_err() {
+                    println!("Failed to remove locks");
+                    std::process::exit(1);
+                }
+            },
+            Some(Commands::Copy { from, to, init, snapshot, latest, destination_password }) => {
+                let key = self.ask_for_key_with_prompt(from, "Enter the password for the source vault: ");
+                let source_vault = open_vault(from, &key);
+
+                let snapshot_ids = match select_snapshots(&source_vault, snapshot, *latest) {
+                    Ok(snapshot_ids) => snapshot_ids,
+                    Err(err) => {
+                        print_selector_error(err);
+                        std::process::exit(1);
+                    }
+                };
+
+                let destination_source = self.destination_password_source(destination_password);
+                let destination_key = self.ask_for_destination_key(to, destination_password, &destination_source, key);
+                let mut destination_vault = open_or_create_vault(to, &destination_key, &destination_source, &self.kdf, *init);
+
+                match source_vault.copy_snapshots(&mut destination_vault, &snapshot_ids) {
+                    Ok(report) => println!(
+                        "Copied {} snapshots ({} already present), {} blobs transferred ({} bytes), {} blobs already present",
+                        report.snapshots_copied, report.snapshots_skipped, report.blobs_copied, report.bytes_copied, report.blobs_skipped
+                    ),
+                    Err(err) => {
+                        println!("Failed to copy snapshots: {}", err);
+                        std::process::exit(1);
+                    }
+                }
+            },
+            Some(Commands::Mirror { from, to, init, verify_data, report, destination_password }) => {
+                let key = self.ask_for_key_with_prompt(from, "Enter the password for the source vault: ");
+                let source_vault = open_vault(from, &key);
+
+                let destination_source = self.destination_password_source(destination_password);
+                let destination_key = self.ask_for_destination_key(to, destination_password, &destination_source, key);
+                let mut destination_vault = open_or_create_vault(to, &destination_key, &destination_source, &self.kdf, *init);
+
+                let mirror_report = match source_vault.mirror(&mut destination_vault, *verify_data) {
+                    Ok(mirror_report) => mirror_report,
+                    Err(err) => {
+                        println!("Failed to mirror vault: {}", err);
+                        std::process::exit(1);
+                    }
+                };
+
+                println!("Snapshots added: {}", mirror_report.snapshots_added.len());
+                println!("Snapshots updated: {}", mirror_report.snapshots_updated.len());
+                println!("Snapshots removed: {}", mirror_report.snapshots_removed.len());
+                println!("Blobs copied: {} ({} bytes)", mirror_report.blobs_copied, mirror_report.bytes_copied);
+                println!("Blobs deleted: {}", mirror_report.blobs_deleted);
+                println!("Blobs verified: {}", mirror_report.blobs_verified);
+                println!("Blobs repaired: {}", mirror_report.blobs_repaired);
+
+                for error in &mirror_report.verify_errors {
+                    println!("Verification error: {}", error);
+                }
+
+                if let Some(report) = report {
+                    if fs::write(report, serde_json::to_string_pretty(&mirror_report).unwrap()).is_err() {
+                        println!("Failed to write report {}", report.display());
+                        std::process::exit(1);
+                    }
+                }
+
+                if !mirror_report.verify_errors.is_empty() {
+                    std::process::exit(1);
+                }
+            },
+            Some(Commands::Serve { root, listen, tls_cert, tls_key, htpasswd, admin_htpasswd, append_only }) => {
+                let options = ServerOptions {
+                    listen: listen.clone(),
+                    root: root.clone(),
+                    tls_cert: tls_cert.clone(),
+                    tls_key: tls_key.clone(),
+                    htpasswd: htpasswd.clone(),
+                    admin_htpasswd: admin_htpasswd.clone(),
+                    append_only: *append_only,
+                };
+
+                if server::serve(options).is_err() {
+                    std::process::exit(1);
+                }
+            },
+            Some(Commands::KdfBenchmark { target_ms, algorithm, memlimit }) => {
+                println!("Measuring the password KDF, this takes a few seconds...");
+
+                let (params, elapsed) = match kdf::benchmark(*algorithm, *memlimit, Duration::from_millis(*target_ms)) {
+                    Ok(result) => result,
                     Err(_) => {
-                        println!("Failed to open vault");
+                        println!("Failed to run the KDF, the memory limit may be too high for this machine");
                         std::process::exit(1);
                     }
                 };
 
-                match snapshot {
-                    Some(snapshot) => {
-                        backup_vault.list_snapshot_contents(snapshot);
+                println!("Unlocking takes {} ms with opslimit {} and memlimit {} MiB", elapsed.as_millis(), params.opslimit, params.memlimit / (1024 * 1024));
+                println!("Create vaults with: --kdf-algorithm {} --kdf-opslimit {} --kdf-memlimit {}", params.algorithm.as_str(), params.opslimit, params.memlimit);
+            },
+            Some(Commands::Key { command: KeyCommands::Generate { output } }) => {
+                if let Err(err) = Keyfile::generate().write(output) {
+                    println!("{}", keyfile_error_message(err));
+                    std::process::exit(1);
+                }
+
+                println!("Keyfile written to {}, create a vault with it using --keyfile", output.display());
+            },
+            Some(Commands::Key { command: KeyCommands::Export { vault, output } }) => {
+                let key = self.ask_for_key(vault);
+
+                let backup_vault = open_vault(vault, &key);
+
+                match backup_vault.export_key(output) {
+                    Ok(_) => {},
+                    Err(BackupError::VaultFileOpenError) => {
+                        println!("Failed to export the key to {}, it must not exist yet", output.display());
+                        std::process::exit(1);
                     },
-                    None => {
-                        backup_vault.list_snapshot_contents(&backup_vault.snapshots.last().unwrap().snapshot_id);
-                    }                    
+                    Err(_) => std::process::exit(1),
                 }
+
+                println!("Keyfile written to {}, it unlocks the vault without the password, keep it safe", output.display());
+            },
+            Some(Commands::ListSnapshotContents { vault, snapshot }) => {
+                let key = self.ask_for_key(vault);
+
+                let backup_vault = open_vault(vault, &key);
+                let snapshot_id = select_snapshot(&backup_vault, snapshot).snapshot_id.clone();
+
+                backup_vault.list_snapshot_contents(&snapshot_id);
             },
             None => {
                 Cli::command().print_help().unwrap();
@@ -175,17 +1194,473 @@ impl Cli {
     }
 }
 
-fn ask_for_password() -> String {
-    print!("Enter the password for the backup vault: ");
+/// The options of a backup, from the backup command, a job of the config file or both.
+#[derive(Default)]
+struct BackupPlan {
+    target: Option<PathBuf>,
+    init: bool,
+    files: Vec<PathBuf>,
+    source: Option<StreamSource>,
+    stdin_filename: Option<PathBuf>,
+    compression: Option<Compression>,
+    exclude: ExcludeOptions,
+    hooks: Hooks,
+    tags: Vec<String>,
+    description: Option<String>,
+    password: PasswordArgs,
+}
+
+impl BackupPlan {
+    /// Fills in what wasn't given from a job, lists like excludes and tags are merged. The job's
+    /// password source is only used if none was given, so a password file doesn't lose against a job's command.
+    fn or_job(self, job: &Job) -> BackupPlan {
+        let (files, source) = match (self.files.is_empty() && self.source.is_none(), job.stdin_from_command.is_empty()) {
+            (true, true) => (job.sources.clone(), None),
+            (true, false) => (vec![], Some(StreamSource::Command(job.stdin_from_command.clone()))),
+            (false, _) => (self.files, self.source),
+        };
+
+        let password_given = self.password.password_file.is_some() || self.password.password_command.is_some();
+        let job_exclude = job.exclude_options().unwrap_or_default();
+
+        BackupPlan {
+            target: self.target.or(Some(job.vault.clone())),
+            init: self.init || job.init,
+            files,
+            source,
+            stdin_filename: self.stdin_filename.or(job.stdin_filename.clone()),
+            compression: self.compression.or(job.compression().unwrap_or_default()),
+            exclude: ExcludeOptions {
+                excludes: [job_exclude.excludes, self.exclude.excludes].concat(),
+                iexcludes: [job_exclude.iexcludes, self.exclude.iexcludes].concat(),
+                exclude_files: [job_exclude.exclude_files, self.exclude.exclude_files].concat(),
+                includes: [job_exclude.includes, self.exclude.includes].concat(),
+                exclude_caches: self.exclude.exclude_caches || job_exclude.exclude_caches,
+                exclude_larger_than: self.exclude.exclude_larger_than.or(job_exclude.exclude_larger_than),
+                one_file_system: self.exclude.one_file_system || job_exclude.one_file_system,
+            },
+            hooks: Hooks {
+                pre: self.hooks.pre.or(job.hooks.pre.clone()),
+                post: self.hooks.post.or(job.hooks.post.clone()),
+                on_error: self.hooks.on_error.or(job.hooks.on_error.clone()),
+                continue_on_pre_failure: self.hooks.continue_on_pre_failure || job.hooks.continue_on_pre_failure,
+            },
+            tags: [job.tags.clone(), self.tags].concat(),
+            description: self.description.or(job.description.clone()),
+            password: PasswordArgs {
+                password_file: if password_given { self.password.password_file } else { job.password_file.clone() },
+                password_command: if password_given { self.password.password_command } else { job.password_command.clone() },
+                keyfile: self.password.keyfile.or(job.keyfile.clone()),
+                with_password: self.password.with_password || job.with_password,
+            },
+        }
+    }
+}
+
+fn open_vault(vault: &Path, key: &VaultKey) -> BackupVault {
+    match BackupVault::open(vault, key) {
+        Ok(vault) => vault,
+        Err(err) => {
+            println!("{}", open_error_message(err));
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Only creates the vault with `init`, so that a mistyped location isn't silently turned into a new vault.
+fn open_or_create_vault(vault: &Path, key: &VaultKey, password_source: &PasswordSource, kdf: &KdfArgs, init: bool) -> BackupVault {
+    or_exit(try_open_or_create_vault(vault, key, password_source, kdf, init))
+}
+
+/// Like `open_or_create_vault`, but returns what went wrong instead of exiting.
+fn try_open_or_create_vault(vault: &Path, key: &VaultKey, password_source: &PasswordSource, kdf: &KdfArgs, init: bool) -> Result<BackupVault, String> {
+    match BackupVault::open(vault, key) {
+        Ok(vault) => Ok(vault),
+        Err(BackupError::VaultDoesNotExist) if init => try_create_vault(vault, key, password_source, &kdf.to_params()),
+        Err(BackupError::VaultDoesNotExist) => Err(format!("Vault {} does not exist, create it with the init command or pass --init", vault.display())),
+        Err(err) => Err(open_error_message(err)),
+    }
+}
+
+fn create_vault(vault: &Path, key: &VaultKey, password_source: &PasswordSource, kdf: &KdfParams) -> BackupVault {
+    or_exit(try_create_vault(vault, key, password_source, kdf))
+}
+
+fn try_create_vault(vault: &Path, key: &VaultKey, password_source: &PasswordSource, kdf: &KdfParams) -> Result<BackupVault, String> {
+    if let Some(password) = &key.password {
+        if password_source.is_interactive() {
+            let confirmation = prompt_password("Repeat the password for the new vault: ").unwrap_or_default();
+
+            if *confirmation != **password {
+                return Err("Passwords do not match".to_string());
+            }
+        }
+    }
+
+    BackupVault::create(vault, key, kdf).map_err(|_| "Failed to create vault".to_string())
+}
+
+/// Prints the message of an error and exits.
+fn or_exit<T>(result: Result<T, String>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(message) => {
+            println!("{}", message);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Opens the vault and runs a backup between its hooks, and exits if anything failed. The vault
+/// is opened after the pre hook, which may mount the drive it is on.
+fn backup_with_hooks(vault: &Path, hooks: &Hooks, open: impl FnOnce() -> Result<BackupVault, String>, backup: impl FnOnce(&mut BackupVault) -> Result<String, BackupError>) -> BackupVault {
+    let started = Instant::now();
+    let mut context = HookContext {
+        vault: vault.to_path_buf(),
+        ..Default::default()
+    };
+
+    if let Err(err) = hooks.run(HookStage::Pre, &context) {
+        let message = hook_error_message(HookStage::Pre, err);
+        println!("{}", message);
+
+        if !hooks.continue_on_pre_failure {
+            context.error = Some(message);
+            run_error_hook(hooks, &context);
+
+            println!("Backup aborted, pass --continue-on-pre-hook-failure to back up anyway");
+            std::process::exit(1);
+        }
+    }
+
+    let mut backup_vault = match open() {
+        Ok(backup_vault) => backup_vault,
+        Err(message) => {
+            println!("{}", message);
+            context.error = Some(message);
+            run_error_hook(hooks, &context);
+
+            std::process::exit(1);
+        }
+    };
+
+    let result = backup(&mut backup_vault);
+    context.duration = started.elapsed().as_secs();
+
+    match result {
+        Ok(snapshot_id) => {
+            if let Some(snapshot) = backup_vault.snapshots.iter().find(|snapshot| snapshot.snapshot_id == snapshot_id) {
+                context.files = snapshot.snapshot_files.len();
+                context.bytes = snapshot.snapshot_files.iter().map(|vault_file| vault_file.file_size).sum();
+            }
+
+            context.snapshot_id = Some(snapshot_id);
+
+            // The snapshot is kept, but whatever the post hook should have done, like unmounting
+            // the drive or reporting success, didn't happen, so this counts as a failure
+            if let Err(err) = hooks.run(HookStage::Post, &context) {
+                let message = hook_error_message(HookStage::Post, err);
+                println!("{}", message);
+                context.error = Some(message);
+                run_error_hook(hooks, &context);
+
+                std::process::exit(1);
+            }
+        },
+        Err(err) => {
+            println!("Failed to back up: {}", err);
+            context.error = Some(format!("Failed to back up: {}", err));
+            run_error_hook(hooks, &context);
+
+            std::process::exit(1);
+        }
+    }
+
+    backup_vault
+}
+
+fn run_error_hook(hooks: &Hooks, context: &HookContext) {
+    if let Err(err) = hooks.run(HookStage::Error, context) {
+        println!("{}", hook_error_message(HookStage::Error, err));
+    }
+}
+
+fn hook_error_message(stage: HookStage, err: HookError) -> String {
+    match err {
+        HookError::SpawnError(err) => format!("Failed to run the {} hook: {}", stage.as_str(), err),
+        HookError::Failed(status) => format!("The {} hook failed with {}", stage.as_str(), status),
+    }
+}
+
+/// Asks a yes/no question on the terminal, anything but yes counts as no.
+fn confirm(question: &str) -> bool {
+    if !std::io::stdin().is_terminal() {
+        println!("{} Pass --yes to confirm without a terminal", question);
+        return false;
+    }
+
+    print!("{} [y/N] ", question);
     std::io::stdout().flush().unwrap();
-    let mut password = String::new();
-    std::io::stdin().read_line(&mut password).unwrap();
-    password.trim().to_string()
+
+    let mut answer = String::new();
+
+    match std::io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+fn restore_filter(paths: Vec<PathBuf>, includes: &[String], excludes: &[String]) -> RestoreFilter {
+    match RestoreFilter::new(paths, includes, excludes) {
+        Ok(filter) => filter,
+        Err(err) => {
+            print_filter_error(err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn file_filter(options: ExcludeOptions) -> FileFilter {
+    match FileFilter::new(options) {
+        Ok(filter) => filter,
+        Err(err) => {
+            print_filter_error(err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn print_filter_error(err: FilterError) {
+    match err {
+        FilterError::InvalidPattern(err) => println!("Invalid pattern: {}", err),
+        FilterError::ExcludeFileReadError(path) => println!("Failed to read exclude file {}", path.display()),
+    }
+}
+
+/// Errors go to stderr, stdout may carry the data.
+fn exit_on_dump_error(result: Result<(), DumpError>, snapshot: &Snapshot) {
+    match result {
+        Ok(_) => {},
+        Err(DumpError::NotFound(path)) => {
+            eprintln!("No file {} in snapshot {}", path.display(), snapshot.snapshot_id);
+            std::process::exit(1);
+        },
+        Err(DumpError::IsDirectory(path)) => {
+            eprintln!("{} is a directory, dump it with --archive tar or --archive zip", path.display());
+            std::process::exit(1);
+        },
+        Err(DumpError::VaultError(err)) => {
+            eprintln!("Failed to read the file from the vault: {}", err);
+            std::process::exit(1);
+        },
+        // A closed pipe, like piping into head, is not an error
+        Err(DumpError::WriteError(err)) if err.kind() == std::io::ErrorKind::BrokenPipe => {},
+        Err(DumpError::WriteError(err)) => {
+            eprintln!("Failed to write: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn select_snapshot<'a>(vault: &'a BackupVault, selector: &SnapshotSelector) -> &'a Snapshot {
+    match selector.find(&vault.snapshots) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            print_selector_error(err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn print_selector_error(err: SelectorError) {
+    match err {
+        SelectorError::NoSnapshots => println!("The vault has no snapshots"),
+        SelectorError::NotFound(selector) => println!("No snapshot matches {}", selector),
+        SelectorError::Ambiguous(selector, snapshot_ids) => {
+            println!("{} matches several snapshots, use a longer id:", selector);
+
+            for snapshot_id in snapshot_ids {
+                println!("- {}", snapshot_id);
+            }
+        }
+    }
+}
+
+fn open_error_message(err: BackupError) -> String {
+    match err {
+        BackupError::VaultWrongPassword => "Wrong password or keyfile".to_string(),
+        BackupError::VaultKeyfileRequired => "The vault is unlocked with a keyfile, pass it with --keyfile".to_string(),
+        BackupError::VaultPasswordRequired => "The vault needs its password in addition to the keyfile".to_string(),
+        BackupError::VaultUnsupportedVersion => "Update quicky_backup to open this vault".to_string(),
+        err => format!("Failed to open vault: {}", err),
+    }
+}
+
+impl Cli {
+    /// Runs a backup with its hooks and returns the vault it went to, exits if it failed.
+    fn backup(&self, plan: &BackupPlan) -> BackupVault {
+        let Some(target) = &plan.target else {
+            println!("The backup needs a --target or a --profile");
+            std::process::exit(1);
+        };
+
+        let password_source = plan.password.to_source();
+
+        if matches!(plan.source, Some(StreamSource::Stdin)) && (plan.password.keyfile.is_none() || plan.password.with_password) && password_source.reads_stdin() {
+            println!("The password can't be read from stdin with --stdin, pass it with --password-file, --password-command or QUICKY_PASSWORD");
+            std::process::exit(1);
+        }
+
+        let filter = file_filter(plan.exclude.clone());
+
+        let options = BackupOptions {
+            compression: plan.compression.unwrap_or(Compression::Auto),
+            filter,
+            tags: plan.tags.clone(),
+            description: plan.description.clone(),
+        };
+
+        let stdin_filename = plan.stdin_filename.clone().unwrap_or(PathBuf::from("stdin"));
+
+        let open = || {
+            let key = try_read_vault_key(target, &plan.password.keyfile, plan.password.with_password, &password_source, "Enter the password for the backup vault: ")?;
+            try_open_or_create_vault(target, &key, &password_source, &self.kdf, plan.init)
+        };
+
+        backup_with_hooks(target, &plan.hooks, open, |backup_vault| match &plan.source {
+            Some(source) => backup_vault.backup_stream(source, &stdin_filename, &options),
+            None => backup_vault.backup(&plan.files, &options),
+        })
+    }
+
+    /// A job of the config file given with --config or of the default one, exits if it can't be used.
+    fn load_job(&self, name: &str) -> Job {
+        let path = match self.config.clone().or_else(Config::default_path) {
+            Some(path) => path,
+            None => {
+                print_profile_error(ProfileError::NoConfigPath);
+                std::process::exit(1);
+            }
+        };
+
+        match Config::load(&path).and_then(|config| config.job(name).cloned()) {
+            Ok(job) => job,
+            Err(err) => {
+                print_profile_error(err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    fn ask_for_key(&self, vault: &Path) -> VaultKey {
+        self.ask_for_key_with_prompt(vault, "Enter the password for the backup vault: ")
+    }
+
+    fn ask_for_key_with_prompt(&self, vault: &Path, prompt: &str) -> VaultKey {
+        read_vault_key(vault, &self.password.keyfile, self.password.with_password, &self.password.to_source(), prompt)
+    }
+
+    fn destination_password_source(&self, destination_password: &DestinationPasswordArgs) -> PasswordSource {
+        PasswordSource {
+            password_file: destination_password.destination_password_file.clone(),
+            password_command: destination_password.destination_password_command.clone(),
+        }
+    }
+
+    /// Without destination key options the user is asked for a password, which may be left empty to
+    /// reuse the source key. Without a terminal to ask on, the source key is only reused with `--reuse-source-key`.
+    fn ask_for_destination_key(&self, vault: &Path, destination_password: &DestinationPasswordArgs, destination_source: &PasswordSource, key: VaultKey) -> VaultKey {
+        let prompt = "Enter the password for the destination vault: ";
+
+        if destination_password.destination_keyfile.is_some() {
+            return read_vault_key(vault, &destination_password.destination_keyfile, destination_password.destination_with_password, destination_source, prompt);
+        }
+
+        if destination_source.password_file.is_some() || destination_source.password_command.is_some() {
+            return VaultKey::from_password(read_password_or_exit(destination_source, prompt));
+        }
+
+        if destination_password.reuse_source_key {
+            return key;
+        }
+
+        if !self.password.to_source().is_interactive() {
+            println!("No password or keyfile given for the destination vault, use --destination-password-file, --destination-password-command, --destination-keyfile or --reuse-source-key");
+            std::process::exit(1);
+        }
+
+        match prompt_password("Enter the password for the destination vault (empty to reuse the source key): ") {
+            Ok(destination_password) if !destination_password.is_empty() => VaultKey::from_password(destination_password),
+            Ok(_) => key,
+            Err(_) => {
+                println!("Failed to read password");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// With a keyfile the password is only read if the vault needs it too, or for a new vault created `with_password`.
+fn read_vault_key(vault: &Path, keyfile: &Option<PathBuf>, with_password: bool, password_source: &PasswordSource, prompt: &str) -> VaultKey {
+    or_exit(try_read_vault_key(vault, keyfile, with_password, password_source, prompt))
+}
+
+/// Like `read_vault_key`, but returns what went wrong instead of exiting.
+fn try_read_vault_key(vault: &Path, keyfile: &Option<PathBuf>, with_password: bool, password_source: &PasswordSource, prompt: &str) -> Result<VaultKey, String> {
+    let keyfile = match keyfile {
+        Some(keyfile) => keyfile,
+        None => return Ok(VaultKey::from_password(read_password(password_source, prompt)?)),
+    };
+
+    let keyfile = Keyfile::read(keyfile).map_err(keyfile_error_message)?;
+
+    let needs_password = match BackupVault::needs_password(vault, &keyfile) {
+        Ok(needs_password) => needs_password,
+        Err(_) => with_password,
+    };
+
+    Ok(VaultKey {
+        password: needs_password.then(|| read_password(password_source, prompt)).transpose()?,
+        keyfile: Some(keyfile),
+    })
+}
+
+fn print_profile_error(err: ProfileError) {
+    match err {
+        ProfileError::NoConfigPath => println!("There is no home directory to find the config file in, pass it with --config"),
+        ProfileError::ReadError(path, err) => println!("Failed to read config file {}: {}", path.display(), err),
+        ProfileError::ParseError(path, err) => println!("Invalid config file {}: {}", path.display(), err),
+        ProfileError::UnknownJob(name, jobs) if jobs.is_empty() => println!("There is no job {}, the config file has no jobs", name),
+        ProfileError::UnknownJob(name, jobs) => println!("There is no job {}, the config file has: {}", name, jobs.join(", ")),
+        ProfileError::InvalidJob(name, err) => println!("Job {} can't be run: {}", name, err),
+    }
+}
+
+fn keyfile_error_message(err: KeyfileError) -> String {
+    match err {
+        KeyfileError::ReadError(path) => format!("Failed to read keyfile {}", path.display()),
+        KeyfileError::WriteError(path) => format!("Failed to write keyfile {}, it must not exist yet", path.display()),
+        KeyfileError::InvalidKeyfile(path) => format!("{} is not a keyfile", path.display()),
+    }
+}
+
+fn read_password_or_exit(password_source: &PasswordSource, prompt: &str) -> Zeroizing<String> {
+    or_exit(read_password(password_source, prompt))
+}
+
+fn read_password(password_source: &PasswordSource, prompt: &str) -> Result<Zeroizing<String>, String> {
+    password_source.read(prompt).map_err(|err| match err {
+        PasswordError::FileReadError(path) => format!("Failed to read password file {}", path.display()),
+        PasswordError::CommandError(err) => format!("Failed to run password command: {}", err),
+        PasswordError::EmptyPassword => "The password can't be empty".to_string(),
+        PasswordError::PromptError => "Failed to read password".to_string(),
+    })
 }
 
+#[allow(clippy::ptr_arg)]
 fn _naive_copy_file(input: &PathBuf, output_dir: &PathBuf) -> std::io::Result<()> {
     // Create the output directory if it doesn't exist
-    fs::create_dir_all(&output_dir)?;
+    fs::create_dir_all(output_dir)?;
     
     let file_name = input
         .file_name()
@@ -197,16 +1672,16 @@ fn _naive_copy_file(input: &PathBuf, output_dir: &PathBuf) -> std::io::Result<()
     
     let dest_path = output_dir.join(file_name);
 
-    fs::copy(&file_path, &dest_path)?;
+    fs::copy(file_path, &dest_path)?;
 
     Ok(())
 }
 
 fn _naive_copy_dir(input_dir: &PathBuf, output_dir: &PathBuf) -> std::io::Result<()> {
     // Create the output directory if it doesn't exist
-    fs::create_dir_all(&output_dir)?;
+    fs::create_dir_all(output_dir)?;
 
-    for entry in fs::read_dir(&input_dir)? {
+    for entry in fs::read_dir(input_dir)? {
         let entry = entry?;
 
         if entry.file_type()?.is_dir() {
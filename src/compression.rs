@@ -0,0 +1,160 @@
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+const ZSTD_DEFAULT_LEVEL: i32 = 3;
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Extensions of formats that are compressed already, compressing them again only costs time.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "avi", "br", "bz2", "docx", "epub", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg",
+    "lz4", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "odp", "ods", "odt", "ogg", "opus", "pdf", "png",
+    "pptx", "rar", "tgz", "txz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// Magic numbers of compressed formats, for files with missing or misleading extensions.
+const COMPRESSED_MAGIC: &[&[u8]] = &[
+    b"\x89PNG",
+    b"\xFF\xD8\xFF",
+    b"GIF8",
+    b"%PDF",
+    b"PK\x03\x04",
+    b"\x1F\x8B",
+    b"\x28\xB5\x2F\xFD",
+    b"\xFD7zXZ\x00",
+    b"BZh",
+    b"7z\xBC\xAF\x27\x1C",
+    b"Rar!",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum CompressionError {
+    UnknownCodec,
+    DecompressionError,
+}
+
+/// The codec a blob payload is stored with, recorded in the blob header.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Codec {
+    None = 0,
+    Zstd = 1,
+}
+
+impl Codec {
+    pub fn from_byte(byte: u8) -> Result<Codec, CompressionError> {
+        match byte {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Zstd),
+            _ => Err(CompressionError::UnknownCodec),
+        }
+    }
+}
+
+/// How file contents are compressed before encryption.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    /// Store everything uncompressed
+    Off,
+    /// Zstd with the default level, skipping formats that are compressed already
+    Auto,
+    /// Zstd with the given level for every file
+    Level(i32),
+}
+
+impl Compression {
+    /// Parses `off`, `auto` or a zstd level.
+    pub fn parse(value: &str) -> Result<Compression, String> {
+        match value {
+            "off" => Ok(Compression::Off),
+            "auto" => Ok(Compression::Auto),
+            level => match level.parse::<i32>() {
+                Ok(level) if zstd::compression_level_range().contains(&level) => Ok(Compression::Level(level)),
+                _ => Err(format!("expected off, auto or a zstd level in {:?}", zstd::compression_level_range())),
+            },
+        }
+    }
+}
+
+/// Compresses `data` if worth it, returning the codec that was used and the payload.
+pub fn compress(data: &[u8], file_name: &str, compression: Compression) -> (Codec, Vec<u8>) {
+    let level = match compression {
+        Compression::Off => return (Codec::None, data.to_vec()),
+        Compression::Auto if is_compressed_format(file_name, data) => return (Codec::None, data.to_vec()),
+        Compression::Auto => ZSTD_DEFAULT_LEVEL,
+        Compression::Level(level) => level,
+    };
+
+    match zstd::bulk::compress(data, level) {
+        Ok(compressed) if compressed.len() < data.len() => (Codec::Zstd, compressed),
+        _ => (Codec::None, data.to_vec()),
+    }
+}
+
+pub fn decompress(codec: Codec, payload: Vec<u8>) -> Result<Vec<u8>, CompressionError> {
+    match codec {
+        Codec::None => Ok(payload),
+        Codec::Zstd => match zstd::decode_all(&payload[..]) {
+            Ok(data) => Ok(data),
+            Err(_) => Err(CompressionError::DecompressionError),
+        },
+    }
+}
+
+/// Guesses whether data is compressed already from its extension, its magic number and how well a sample compresses.
+pub fn is_compressed_format(file_name: &str, data: &[u8]) -> bool {
+    let extension = Path::new(file_name).extension().map(|extension| extension.to_string_lossy().to_lowercase());
+
+    if extension.is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&extension.as_str())) {
+        return true;
+    }
+
+    if COMPRESSED_MAGIC.iter().any(|magic| data.starts_with(magic)) {
+        return true;
+    }
+
+    let sample = &data[..data.len().min(SAMPLE_SIZE)];
+
+    match zstd::bulk::compress(sample, 1) {
+        Ok(compressed) => compressed.len() * 100 > sample.len() * 95,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit. ".repeat(100);
+
+        let (codec, payload) = compress(&data, "lorem.txt", Compression::Auto);
+        assert_eq!(codec, Codec::Zstd);
+        assert!(payload.len() < data.len());
+        assert_eq!(decompress(codec, payload).unwrap(), data);
+
+        let (codec, payload) = compress(&data, "lorem.txt", Compression::Off);
+        assert_eq!(codec, Codec::None);
+        assert_eq!(decompress(codec, payload).unwrap(), data);
+    }
+
+    #[test]
+    fn skips_compressed_formats() {
+        let data = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit. ".repeat(100);
+
+        assert!(is_compressed_format("Orar.png", &data));
+        assert!(is_compressed_format("no_extension", b"%PDF-1.7 ..."));
+        assert!(!is_compressed_format("lorem.txt", &data));
+        assert_eq!(compress(&data, "Orar.PNG", Compression::Auto).0, Codec::None);
+        assert_eq!(compress(&data, "Orar.png", Compression::Level(19)).0, Codec::Zstd);
+    }
+
+    #[test]
+    fn parse_compression() {
+        assert_eq!(Compression::parse("off"), Ok(Compression::Off));
+        assert_eq!(Compression::parse("auto"), Ok(Compression::Auto));
+        assert_eq!(Compression::parse("9"), Ok(Compression::Level(9)));
+        assert!(Compression::parse("fast").is_err());
+        assert!(Compression::parse("99").is_err());
+    }
+}
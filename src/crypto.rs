@@ -6,15 +6,39 @@ use secretbox::NONCEBYTES;
 use pwhash::SALTBYTES;
 
 use serde::{Serialize, Deserialize};
+use zeroize::Zeroizing;
 
+use crate::kdf::{KdfError, KdfParams};
+use crate::keyfile::Keyfile;
+
+const KEY_CHECK_CONTEXT: &[u8] = b"quicky_backup key check";
 const NONCESALTBYTES: usize = NONCEBYTES + SALTBYTES;
 
 #[derive(Debug, PartialEq)]
 pub enum CryptoError {
     DecryptionError,
+    KdfError(KdfError),
 }
 
+/// The public part of the crypto setup, stored in the vault index. Vaults written by older
+/// versions also stored the key next to it, which is ignored and dropped on the next write.
 #[derive(Serialize, Deserialize)]
+pub struct CryptoParams {
+    nonce: secretbox::Nonce,
+    salt: pwhash::Salt,
+}
+
+impl CryptoParams {
+    pub fn export(&self) -> [u8; NONCESALTBYTES] {
+        let mut salt_nonce = [0; NONCESALTBYTES];
+        salt_nonce[..SALTBYTES].copy_from_slice(&self.salt.0);
+        salt_nonce[SALTBYTES..].copy_from_slice(&self.nonce.0);
+
+        salt_nonce
+    }
+}
+
+/// Holds the vault key, which is zeroed when dropped and can't be serialized.
 pub struct CryptoModule {
     key: secretbox::Key,
     nonce: secretbox::Nonce,
@@ -22,25 +46,32 @@ pub struct CryptoModule {
 }
 
 impl CryptoModule {
+    /// Derives the key from the password like vaults created before key slots.
+    #[cfg(test)]
     pub fn new(pass: &[u8]) -> CryptoModule {
         sodiumoxide::init().unwrap();
 
         let salt = pwhash::gen_salt();
-        let mut k = secretbox::gen_key();
-        let secretbox::Key(ref mut k) = k;
-
-        let mut key_arr0 = [0; KEYBYTES];
-        let key_arr1 = pwhash::derive_key(k, pass, &salt, pwhash::OPSLIMIT_INTERACTIVE, pwhash::MEMLIMIT_INTERACTIVE).unwrap();
-        key_arr0.copy_from_slice(&key_arr1);
-        let key = secretbox::Key(key_arr0);
 
         CryptoModule {
-            key,
+            key: secretbox::Key(*derive_key(None, Some(pass), &salt, &KdfParams::default()).unwrap()),
             nonce: secretbox::gen_nonce(),
             salt,
         }
     }
 
+    /// A random vault key, stored wrapped in key slots, see `wrap_key`.
+    pub fn generate() -> CryptoModule {
+        sodiumoxide::init().unwrap();
+
+        CryptoModule {
+            key: secretbox::gen_key(),
+            nonce: secretbox::gen_nonce(),
+            salt: pwhash::gen_salt(),
+        }
+    }
+
+    /// Derives the key from the password, for vaults created before key slots.
     pub fn import(pass: &[u8], byte_array: [u8; NONCESALTBYTES]) -> CryptoModule {
         sodiumoxide::init().unwrap();
 
@@ -53,44 +84,155 @@ impl CryptoModule {
         let salt = pwhash::Salt(salt_array);
         let nonce = secretbox::Nonce(nonce_array);
 
-        let mut k = secretbox::gen_key();
-        let secretbox::Key(ref mut k) = k;
+        CryptoModule {
+            key: secretbox::Key(*derive_key(None, Some(pass), &salt, &KdfParams::default()).unwrap()),
+            nonce,
+            salt,
+        }
+    }
+
+    /// Like `import`, but the key is the keyfile itself, which is how an exported key unlocks a vault.
+    pub fn import_with_keyfile(keyfile: &Keyfile, byte_array: [u8; NONCESALTBYTES]) -> CryptoModule {
+        sodiumoxide::init().unwrap();
 
-        let mut key_arr0 = [0; KEYBYTES];
-        let key_arr1 = pwhash::derive_key(k, pass, &salt, pwhash::OPSLIMIT_INTERACTIVE, pwhash::MEMLIMIT_INTERACTIVE).unwrap();
-        key_arr0.copy_from_slice(&key_arr1);
-        let key = secretbox::Key(key_arr0);
+        let salt = pwhash::Salt::from_slice(&byte_array[..SALTBYTES]).unwrap();
+        let nonce = secretbox::Nonce::from_slice(&byte_array[SALTBYTES..]).unwrap();
 
         CryptoModule {
-            key,
+            key: secretbox::Key(*keyfile.key()),
             nonce,
             salt,
         }
     }
 
+    /// Seals the vault key with a key derived from the keyfile and/or password and a fresh salt.
+    /// Unwrapping it again is what verifies a password or keyfile.
+    pub fn wrap_key(&self, keyfile: Option<&Keyfile>, pass: Option<&[u8]>, kdf: &KdfParams) -> Result<Vec<u8>, CryptoError> {
+        let salt = pwhash::gen_salt();
+        let wrapping_key = secretbox::Key(*derive_key(keyfile, pass, &salt, kdf)?);
+        let nonce = secretbox::gen_nonce();
+
+        let mut wrapped = salt.0.to_vec();
+        wrapped.extend(nonce.0);
+        wrapped.extend(secretbox::seal(&self.key.0, &nonce, &wrapping_key));
+
+        Ok(wrapped)
+    }
+
+    /// Opens a key wrapped by `wrap_key`, failing if the keyfile or password is wrong.
+    pub fn unwrap_key(wrapped: &[u8], keyfile: Option<&Keyfile>, pass: Option<&[u8]>, kdf: &KdfParams, byte_array: [u8; NONCESALTBYTES]) -> Result<CryptoModule, CryptoError> {
+        sodiumoxide::init().unwrap();
+
+        if wrapped.len() < SALTBYTES + NONCEBYTES {
+            return Err(CryptoError::DecryptionError);
+        }
+
+        let salt = pwhash::Salt::from_slice(&wrapped[..SALTBYTES]).unwrap();
+        let nonce = secretbox::Nonce::from_slice(&wrapped[SALTBYTES..SALTBYTES + NONCEBYTES]).unwrap();
+        let wrapping_key = secretbox::Key(*derive_key(keyfile, pass, &salt, kdf)?);
+
+        let key = match secretbox::open(&wrapped[SALTBYTES + NONCEBYTES..], &nonce, &wrapping_key) {
+            Ok(key) => Zeroizing::new(key),
+            Err(_) => return Err(CryptoError::DecryptionError),
+        };
+
+        let key = match secretbox::Key::from_slice(&key) {
+            Some(key) => key,
+            None => return Err(CryptoError::DecryptionError),
+        };
+
+        Ok(CryptoModule {
+            key,
+            nonce: secretbox::Nonce::from_slice(&byte_array[SALTBYTES..]).unwrap(),
+            salt: pwhash::Salt::from_slice(&byte_array[..SALTBYTES]).unwrap(),
+        })
+    }
+
+    pub fn params(&self) -> CryptoParams {
+        CryptoParams {
+            nonce: self.nonce,
+            salt: self.salt,
+        }
+    }
+
+    #[cfg(test)]
     pub fn export(&self) -> [u8; NONCESALTBYTES] {
-        let mut salt_nonce = [0; NONCESALTBYTES];
-        salt_nonce[..SALTBYTES].copy_from_slice(&self.salt.0);
-        salt_nonce[SALTBYTES..].copy_from_slice(&self.nonce.0);
+        self.params().export()
+    }
 
-        salt_nonce
+    /// The vault key as a keyfile, which unlocks the vault without the password.
+    pub fn export_key(&self) -> Keyfile {
+        Keyfile::from_key(self.key.0)
+    }
+
+    /// A value derived from the key that tells whether a key is the right one without revealing it.
+    pub fn key_check(&self) -> String {
+        key_check(&self.key.0)
     }
 
+    /// Encrypts with the vault nonce like blobs written before the blob header, only tests still need it.
+    #[cfg(test)]
     pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
         secretbox::seal(plaintext, &self.nonce, &self.key)
     }
 
+    /// Decrypts data encrypted with the vault nonce.
     pub fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
         match secretbox::open(ciphertext, &self.nonce, &self.key) {
             Ok(plaintext) => Ok(plaintext),
             Err(_) => Err(CryptoError::DecryptionError)
         }
     }
+
+    /// Encrypts with a fresh random nonce, which is prepended to the ciphertext.
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = secretbox::gen_nonce();
+
+        let mut sealed = nonce.0.to_vec();
+        sealed.extend(secretbox::seal(plaintext, &nonce, &self.key));
+
+        sealed
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if sealed.len() < NONCEBYTES {
+            return Err(CryptoError::DecryptionError);
+        }
+
+        let nonce = secretbox::Nonce::from_slice(&sealed[..NONCEBYTES]).unwrap();
+
+        match secretbox::open(&sealed[NONCEBYTES..], &nonce, &self.key) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(_) => Err(CryptoError::DecryptionError)
+        }
+    }
+}
+
+pub fn key_check(key: &[u8; KEYBYTES]) -> String {
+    blake3::keyed_hash(key, KEY_CHECK_CONTEXT).to_hex().to_string()
+}
+
+/// The keyfile is used as it is, or mixed with the key derived from the password if both are given.
+fn derive_key(keyfile: Option<&Keyfile>, pass: Option<&[u8]>, salt: &pwhash::Salt, kdf: &KdfParams) -> Result<Zeroizing<[u8; KEYBYTES]>, CryptoError> {
+    let derived = match (keyfile, pass) {
+        (Some(keyfile), Some(pass)) => Zeroizing::new(*blake3::keyed_hash(keyfile.key(), &*kdf.derive(pass, &salt.0)?).as_bytes()),
+        (Some(keyfile), None) => Zeroizing::new(*keyfile.key()),
+        (None, pass) => kdf.derive(pass.unwrap_or_default(), &salt.0)?,
+    };
+
+    Ok(derived)
+}
+
+impl From<KdfError> for CryptoError {
+    fn from(err: KdfError) -> Self {
+        CryptoError::KdfError(err)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::kdf::{KdfAlgorithm, KdfProfile};
 
     #[test]
     fn basic_setup() {
@@ -107,6 +249,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::unwrap_or_default)]
     fn happy_path() {
         let pass = "password12345";
         let pass_u8 = pass.as_bytes();
@@ -146,6 +289,58 @@ mod tests {
         assert_eq!(err, CryptoError::DecryptionError)
     }
 
+    #[test]
+    fn seal_open() {
+        let crypto = CryptoModule::new(b"password12345");
+        let plaintext = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit.";
+
+        let sealed1 = crypto.seal(plaintext);
+        let sealed2 = crypto.seal(plaintext);
+
+        assert_ne!(sealed1, sealed2);
+        assert_eq!(crypto.open(&sealed1).unwrap(), plaintext);
+        assert_eq!(crypto.open(&sealed1[..NONCEBYTES]).expect_err("Decryption should fail"), CryptoError::DecryptionError);
+    }
+
+    #[test]
+    fn keyfile() {
+        let keyfile = Keyfile::generate();
+        let plaintext = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit.";
+
+        let crypto = CryptoModule::import_with_keyfile(&keyfile, CryptoModule::generate().export());
+        let sealed = crypto.seal(plaintext);
+
+        let exported = CryptoModule::import_with_keyfile(&crypto.export_key(), crypto.export());
+
+        assert_eq!(crypto.key, secretbox::Key(*keyfile.key()));
+        assert_eq!(exported.open(&sealed).unwrap(), plaintext);
+        assert_eq!(exported.key_check(), crypto.key_check());
+    }
+
+    #[test]
+    fn key_slots() {
+        let keyfile = Keyfile::generate();
+        let crypto = CryptoModule::generate();
+        let plaintext = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit.";
+        let sealed = crypto.seal(plaintext);
+
+        let kdf = KdfParams::from_profile(KdfAlgorithm::Argon2id13, KdfProfile::Interactive).unwrap();
+
+        let password_slot = crypto.wrap_key(None, Some(b"password12345"), &kdf).unwrap();
+        let combined_slot = crypto.wrap_key(Some(&keyfile), Some(b"password12345"), &kdf).unwrap();
+
+        let unwrapped = CryptoModule::unwrap_key(&password_slot, None, Some(b"password12345"), &kdf, crypto.export()).unwrap();
+        assert_eq!(unwrapped.open(&sealed).unwrap(), plaintext);
+
+        let unwrapped = CryptoModule::unwrap_key(&combined_slot, Some(&keyfile), Some(b"password12345"), &kdf, crypto.export()).unwrap();
+        assert_eq!(unwrapped.open(&sealed).unwrap(), plaintext);
+
+        assert!(CryptoModule::unwrap_key(&password_slot, None, Some(b"password54321"), &kdf, crypto.export()).is_err());
+        assert!(CryptoModule::unwrap_key(&password_slot, None, Some(b"password12345"), &KdfParams::default(), crypto.export()).is_err());
+        assert!(CryptoModule::unwrap_key(&combined_slot, Some(&keyfile), None, &kdf, crypto.export()).is_err());
+        assert!(CryptoModule::unwrap_key(&combined_slot, Some(&Keyfile::generate()), Some(b"password12345"), &kdf, crypto.export()).is_err());
+    }
+
     #[test]
     fn key_conversion() {
         let key = secretbox::gen_key();
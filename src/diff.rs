@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::backup_vault::*;
+use crate::filter::FileFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffEntry {
+    pub path: PathBuf,
+    pub change: Change,
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+    pub content_changed: bool,
+    /// Modification time or permissions changed, only known if both sides recorded them
+    pub metadata_changed: bool,
+}
+
+impl DiffEntry {
+    pub fn size_delta(&self) -> i64 {
+        self.new_size.unwrap_or(0) as i64 - self.old_size.unwrap_or(0) as i64
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DiffReport {
+    pub from: String,
+    pub to: String,
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+    pub size_delta: i64,
+    pub entries: Vec<DiffEntry>,
+}
+
+impl DiffReport {
+    fn push(&mut self, entry: DiffEntry) {
+        match entry.change {
+            Change::Added => self.added += 1,
+            Change::Removed => self.removed += 1,
+            Change::Modified => self.modified += 1,
+        }
+
+        self.size_delta += entry.size_delta();
+        self.entries.push(entry);
+    }
+
+    pub fn print(&self) {
+        println!("Comparing {} to {}", self.from, self.to);
+
+        for entry in &self.entries {
+            match entry.change {
+                Change::Added => println!("+ {} ({} bytes)", entry.path.display(), entry.new_size.unwrap_or(0)),
+                Change::Removed => println!("- {} ({} bytes)", entry.path.display(), entry.old_size.unwrap_or(0)),
+                // Like restic, M marks changed content and U changed metadata only
+                Change::Modified if entry.content_changed => println!("M {} ({:+} bytes)", entry.path.display(), entry.size_delta()),
+                Change::Modified => println!("U {}", entry.path.display()),
+            }
+        }
+
+        println!("Added: {}, removed: {}, modified: {}, size change: {:+} bytes", self.added, self.removed, self.modified, self.size_delta);
+    }
+}
+
+/// What is compared of a file, the hash of live files is only computed when their size matches.
+struct FileState {
+    size: u64,
+    hash: Option<String>,
+    modified: Option<u64>,
+    mode: Option<u32>,
+}
+
+impl FileState {
+    fn from_vault_file(vault_file: &VaultFile) -> FileState {
+        FileState {
+            size: vault_file.file_size,
+            hash: Some(vault_file.file_hash.clone()),
+            modified: vault_file.file_modified,
+            mode: vault_file.file_mode,
+        }
+    }
+
+    fn metadata_differs(&self, other: &FileState) -> bool {
+        let differs = |a: Option<u64>, b: Option<u64>| matches!((a, b), (Some(a), Some(b)) if a != b);
+
+        differs(self.modified, other.modified) || differs(self.mode.map(u64::from), other.mode.map(u64::from))
+    }
+}
+
+/// Compares two snapshots by file path.
+pub fn diff_snapshots(from: &Snapshot, to: &Snapshot) -> DiffReport {
+    let old_files = snapshot_states(from, None);
+    let new_files = snapshot_states(to, None);
+
+    let mut report = diff(old_files, new_files, |_| None);
+    report.from = from.snapshot_id.clone();
+    report.to = to.snapshot_id.clone();
+
+    report
+}
+
+/// Compares the files of a snapshot below `dir` with the files in `dir` now. The snapshot has to
+/// store absolute paths, which snapshots of older versions may not.
+pub fn diff_live(snapshot: &Snapshot, dir: &Path, filter: &FileFilter) -> DiffReport {
+    let dir = fs::canonicalize(dir).unwrap_or(dir.to_path_buf());
+    let old_files = snapshot_states(snapshot, Some(&dir));
+    let mut new_files = BTreeMap::new();
+
+    for path in filter.walk(&dir) {
+        match fs::metadata(&path) {
+            Ok(metadata) => {
+                new_files.insert(path, FileState {
+                    size: metadata.len(),
+                    hash: None,
+                    modified: file_modified(&metadata),
+                    mode: file_mode(&metadata),
+                });
+            },
+            Err(err) => println!("Failed to read {}: {}", path.display(), err),
+        }
+    }
+
+    let mut report = diff(old_files, new_files, hash_live_file);
+    report.from = snapshot.snapshot_id.clone();
+    report.to = dir.display().to_string();
+
+    report
+}
+
+fn snapshot_states(snapshot: &Snapshot, below: Option<&Path>) -> BTreeMap<PathBuf, FileState> {
+    snapshot.snapshot_files.iter()
+        .filter(|vault_file| below.is_none_or(|dir| vault_file.file_path.starts_with(dir)))
+        .map(|vault_file| (vault_file.file_path.clone(), FileState::from_vault_file(vault_file)))
+        .collect()
+}
+
+fn diff(old_files: BTreeMap<PathBuf, FileState>, new_files: BTreeMap<PathBuf, FileState>, hash_new: impl Fn(&Path) -> Option<String>) -> DiffReport {
+    let mut report = DiffReport::default();
+    let paths: BTreeSet<&PathBuf> = old_files.keys().chain(new_files.keys()).collect();
+
+    for path in paths {
+        let (old, new) = (old_files.get(path), new_files.get(path));
+
+        let content_changed = match (old, new) {
+            (Some(old), Some(new)) if old.size != new.size => true,
+            (Some(old), Some(new)) => match &new.hash {
+                Some(hash) => old.hash.as_ref() != Some(hash),
+                None => old.hash != hash_new(path),
+            },
+            _ => false,
+        };
+
+        let change = match (old, new) {
+            (None, Some(_)) => Change::Added,
+            (Some(_), None) => Change::Removed,
+            (Some(old), Some(new)) if content_changed || old.metadata_differs(new) => Change::Modified,
+            _ => continue,
+        };
+
+        report.push(DiffEntry {
+            path: path.clone(),
+            change,
+            old_size: old.map(|old| old.size),
+            new_size: new.map(|new| new.size),
+            content_changed,
+            metadata_changed: matches!((old, new), (Some(old), Some(new)) if old.metadata_differs(new)),
+        });
+    }
+
+    report
+}
+
+fn hash_live_file(path: &Path) -> Option<String> {
+    match hash_file(path) {
+        Ok(hash) => Some(hash),
+        Err(err) => {
+            println!("Failed to read {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vault_file(path: &str, content: &[u8], modified: Option<u64>) -> VaultFile {
+        VaultFile {
+            file_name: path.rsplit('/').next().unwrap().to_string(),
+            file_hash: blake3::hash(content).to_hex().to_string(),
+            file_path: PathBuf::from(path),
+            file_size: content.len() as u64,
+            vault_paths: vec![],
+            file_modified: modified,
+            file_mode: None,
+        }
+    }
+
+    fn snapshot(snapshot_id: &str, files: Vec<VaultFile>) -> Snapshot {
+        Snapshot {
+            snapshot_id: snapshot_id.to_string(),
+            snapshot_time: "0".to_string(),
+            snapshot_files: files,
+            hostname: String::new(),
+            username: String::new(),
+            paths: vec![],
+            program_version: String::new(),
+            description: None,
+            tags: vec![],
+        }
+    }
+
+    fn changes(report: &DiffReport) -> Vec<(String, Change, bool, bool)> {
+        report.entries.iter()
+            .map(|entry| (entry.path.display().to_string(), entry.change, entry.content_changed, entry.metadata_changed))
+            .collect()
+    }
+
+    #[test]
+    fn snapshots() {
+        let from = snapshot("a", vec![
+            vault_file("/data/kept", b"same", Some(1)),
+            vault_file("/data/edited", b"old", Some(1)),
+            vault_file("/data/touched", b"same", Some(1)),
+            vault_file("/data/legacy", b"same", None),
+            vault_file("/data/removed", b"gone", Some(1)),
+        ]);
+        let to = snapshot("b", vec![
+            vault_file("/data/kept", b"same", Some(1)),
+            vault_file("/data/edited", b"longer", Some(2)),
+            vault_file("/data/touched", b"same", Some(2)),
+            vault_file("/data/legacy", b"same", Some(2)),
+            vault_file("/data/new", b"new file", Some(2)),
+        ]);
+
+        let report = diff_snapshots(&from, &to);
+
+        assert_eq!(changes(&report), vec![
+            ("/data/edited".to_string(), Change::Modified, true, true),
+            ("/data/new".to_string(), Change::Added, false, false),
+            ("/data/removed".to_string(), Change::Removed, false, false),
+            ("/data/touched".to_string(), Change::Modified, false, true),
+        ]);
+        assert_eq!((report.added, report.removed, report.modified), (1, 1, 2));
+        assert_eq!(report.size_delta, 3 + 8 - 4);
+    }
+
+    #[test]
+    fn live() {
+        let root = std::env::temp_dir().join(format!("quicky_diff_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        let root = fs::canonicalize(&root).unwrap();
+
+        fs::write(root.join("kept"), "same").unwrap();
+        fs::write(root.join("edited"), "new!").unwrap();
+        fs::write(root.join("added"), "added").unwrap();
+
+        let path = |name: &str| root.join(name).display().to_string();
+        let snapshot = snapshot("a", vec![
+            vault_file(&path("kept"), b"same", None),
+            vault_file(&path("edited"), b"old!", None),
+            vault_file(&path("removed"), b"gone", None),
+            vault_file("/elsewhere/ignored", b"other root", None),
+        ]);
+
+        let report = diff_live(&snapshot, &root, &FileFilter::default());
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(changes(&report), vec![
+            (path("added"), Change::Added, false, false),
+            (path("edited"), Change::Modified, true, false),
+            (path("removed"), Change::Removed, false, false),
+        ]);
+    }
+}
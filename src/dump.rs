@@ -0,0 +1,280 @@
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+use zip::write::SimpleFileOptions;
+use zip::CompressionMethod;
+
+use crate::backup_vault::*;
+use crate::filter::RestoreFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarZstd,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn parse(value: &str) -> Result<ArchiveFormat, String> {
+        match value {
+            "tar" => Ok(ArchiveFormat::Tar),
+            "tar.zst" => Ok(ArchiveFormat::TarZstd),
+            "zip" => Ok(ArchiveFormat::Zip),
+            _ => Err(format!("invalid archive format '{}', expected tar, tar.zst or zip", value)),
+        }
+    }
+
+    /// Guesses the format from the extension of an output file, tar if it has none of the known ones.
+    pub fn from_path(path: &Path) -> ArchiveFormat {
+        let name = path.file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default();
+
+        if name.ends_with(".zip") {
+            ArchiveFormat::Zip
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            ArchiveFormat::TarZstd
+        } else {
+            ArchiveFormat::Tar
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DumpError {
+    NotFound(PathBuf),
+    IsDirectory(PathBuf),
+    VaultError(BackupError),
+    WriteError(std::io::Error),
+}
+
+impl From<BackupError> for DumpError {
+    fn from(err: BackupError) -> Self {
+        DumpError::VaultError(err)
+    }
+}
+
+impl From<std::io::Error> for DumpError {
+    fn from(err: std::io::Error) -> Self {
+        DumpError::WriteError(err)
+    }
+}
+
+/// Finds a file of the snapshot by the path it was backed up from or its path relative to a restore target.
+pub fn find_file<'a>(snapshot: &'a Snapshot, path: &Path) -> Result<&'a VaultFile, DumpError> {
+    let found = snapshot.snapshot_files.iter()
+        .find(|vault_file| vault_file.file_path == path || snapshot.restore_path(&vault_file.file_path) == path);
+
+    if let Some(vault_file) = found {
+        return Ok(vault_file);
+    }
+
+    let is_directory = snapshot.snapshot_files.iter()
+        .any(|vault_file| vault_file.file_path.starts_with(path) || snapshot.restore_path(&vault_file.file_path).starts_with(path));
+
+    match is_directory {
+        true => Err(DumpError::IsDirectory(path.to_path_buf())),
+        false => Err(DumpError::NotFound(path.to_path_buf())),
+    }
+}
+
+impl BackupVault {
+    /// Writes the content of a file blob by blob.
+    pub fn dump_file(&self, vault_file: &VaultFile, out: &mut impl Write) -> Result<(), DumpError> {
+        for vault_path in &vault_file.vault_paths {
+            out.write_all(&self.read_blob(vault_path)?)?;
+        }
+
+        out.flush()?;
+
+        Ok(())
+    }
+
+    /// Writes the files of the snapshot below `path`, or all of them, as an archive. Entries are
+    /// named like the files would be restored, e.g. `docs/report.txt` for a backup of `/home/me/docs`.
+    /// In every format a file is written while its blobs are decrypted, one blob at a time.
+    pub fn dump_archive(&self, snapshot: &Snapshot, path: Option<&Path>, format: ArchiveFormat, out: impl Write) -> Result<usize, DumpError> {
+        let filter = match RestoreFilter::new(path.into_iter().map(Path::to_path_buf).collect(), &[], &[]) {
+            Ok(filter) => filter,
+            Err(_) => return Err(DumpError::NotFound(path.map(Path::to_path_buf).unwrap_or_default())),
+        };
+
+        let files: Vec<(&VaultFile, PathBuf)> = snapshot.snapshot_files.iter()
+            .map(|vault_file| (vault_file, snapshot.restore_path(&vault_file.file_path)))
+            .filter(|(vault_file, restore_path)| filter.matches(&vault_file.file_path, restore_path))
+            .collect();
+
+        if files.is_empty() {
+            return Err(DumpError::NotFound(path.map(Path::to_path_buf).unwrap_or_default()));
+        }
+
+        match format {
+            ArchiveFormat::Tar => {
+                self.write_tar(&files, out)?.flush()?;
+            },
+            ArchiveFormat::TarZstd => {
+                let encoder = zstd::Encoder::new(out, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+                self.write_tar(&files, encoder)?.finish()?.flush()?;
+            },
+            ArchiveFormat::Zip => {
+                // A stream writer never seeks, the crc and sizes of every file follow its data
+                let mut zip = zip::ZipWriter::new_stream(out);
+
+                for (vault_file, name) in &files {
+                    // Whether the sizes need zip64 records has to be decided before the data, and
+                    // deflate can grow incompressible data a little
+                    let options = SimpleFileOptions::default()
+                        .compression_method(CompressionMethod::Deflated)
+                        .last_modified_time(zip_time(vault_file.file_modified.unwrap_or(0)))
+                        .unix_permissions(vault_file.file_mode.unwrap_or(0o644))
+                        .large_file(vault_file.file_size.saturating_add(vault_file.file_size / 1024 + 64) >= u32::MAX as u64);
+
+                    zip.start_file(name.to_string_lossy(), options).map_err(io::Error::from)?;
+                    io::copy(&mut BlobReader::new(self, vault_file), &mut zip)?;
+                }
+
+                zip.finish().map_err(io::Error::from)?.flush()?;
+            }
+        }
+
+        Ok(files.len())
+    }
+
+    /// Writes the files with their size, mode and modification time, decrypting one blob at a time.
+    fn write_tar<W: Write>(&self, files: &[(&VaultFile, PathBuf)], out: W) -> Result<W, DumpError> {
+        let mut builder = tar::Builder::new(out);
+
+        for (vault_file, name) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(vault_file.file_size);
+            header.set_mode(vault_file.file_mode.unwrap_or(0o644));
+            header.set_mtime(vault_file.file_modified.unwrap_or(0));
+
+            builder.append_data(&mut header, name, BlobReader::new(self, vault_file))?;
+        }
+
+        Ok(builder.into_inner()?)
+    }
+}
+
+/// Zip archives store the modification time as a DOS date and time with two second precision,
+/// here in UTC. Times before 1980 can't be stored and become 1980-01-01.
+fn zip_time(modified: u64) -> zip::DateTime {
+    chrono::DateTime::from_timestamp(modified as i64, 0)
+        .and_then(|time| zip::DateTime::try_from(time.naive_utc()).ok())
+        .unwrap_or_default()
+}
+
+/// Reads the content of a file, decrypting its blobs when they are reached. It has to be exactly
+/// as long as the size the archive header was written with, or the rest of the archive is garbage.
+struct BlobReader<'a> {
+    vault: &'a BackupVault,
+    blobs: std::slice::Iter<'a, PathBuf>,
+    blob: Cursor<Vec<u8>>,
+    remaining: u64,
+}
+
+impl<'a> BlobReader<'a> {
+    fn new(vault: &'a BackupVault, vault_file: &'a VaultFile) -> BlobReader<'a> {
+        BlobReader { vault, blobs: vault_file.vault_paths.iter(), blob: Cursor::new(vec![]), remaining: vault_file.file_size }
+    }
+}
+
+impl Read for BlobReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = buf.len().min(self.remaining.min(usize::MAX as u64) as usize);
+
+        loop {
+            let read = self.blob.read(&mut buf[..length])?;
+
+            if read > 0 || length == 0 {
+                self.remaining -= read as u64;
+                return Ok(read);
+            }
+
+            match self.blobs.next() {
+                Some(vault_path) => match self.vault.read_blob(vault_path) {
+                    Ok(blob) => self.blob = Cursor::new(blob),
+                    Err(err) => return Err(io::Error::other(format!("failed to read blob {}: {}", vault_path.display(), err))),
+                },
+                None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the blobs are shorter than the file")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+
+    use super::*;
+    use crate::backup_vault::tests::temp_vault;
+
+    #[test]
+    fn dump() {
+        let (root, mut vault) = temp_vault("dump");
+        let source = root.join("data");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("config"), "key = value\n").unwrap();
+        fs::write(source.join("sub/repeated"), "repeated ".repeat(100)).unwrap();
+
+        vault.backup(&vec![source.clone()], &BackupOptions::default()).unwrap();
+        let snapshot = &vault.snapshots[0];
+
+        let mut config = vec![];
+        vault.dump_file(find_file(snapshot, Path::new("data/config")).unwrap(), &mut config).unwrap();
+
+        let mut tar = vec![];
+        vault.dump_archive(snapshot, None, ArchiveFormat::Tar, &mut tar).unwrap();
+
+        let mut zip = vec![];
+        vault.dump_archive(snapshot, Some(&source.join("sub")), ArchiveFormat::Zip, &mut zip).unwrap();
+
+        let mut tar_zstd = vec![];
+        vault.dump_archive(snapshot, None, ArchiveFormat::TarZstd, &mut tar_zstd).unwrap();
+
+        let directory = find_file(snapshot, &source.join("sub"));
+        let missing = find_file(snapshot, Path::new("data/missing"));
+
+        assert_eq!(config, b"key = value\n");
+        assert!(matches!(directory, Err(DumpError::IsDirectory(_))));
+        assert!(matches!(missing, Err(DumpError::NotFound(_))));
+
+        let mut archive = tar::Archive::new(tar.as_slice());
+        let mut entries: Vec<(String, String)> = archive.entries().unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut content = String::new();
+                entry.read_to_string(&mut content).unwrap();
+                (entry.path().unwrap().display().to_string(), content)
+            })
+            .collect();
+        entries.sort();
+
+        assert_eq!(zstd::decode_all(tar_zstd.as_slice()).unwrap(), tar);
+        assert_eq!(entries, vec![
+            ("data/config".to_string(), "key = value\n".to_string()),
+            ("data/sub/repeated".to_string(), "repeated ".repeat(100)),
+        ]);
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(zip)).unwrap();
+        assert_eq!(archive.len(), 1);
+
+        let mut entry = archive.by_index(0).unwrap();
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+
+        assert_eq!((entry.name(), entry.size(), entry.compression()), ("data/sub/repeated", 900, CompressionMethod::Deflated));
+        assert_eq!(entry.unix_mode().map(|mode| mode & 0o7777), find_file(snapshot, Path::new("data/sub/repeated")).unwrap().file_mode);
+        assert_eq!(content, "repeated ".repeat(100));
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(ArchiveFormat::parse("tar.zst"), Ok(ArchiveFormat::TarZstd));
+        assert!(ArchiveFormat::parse("rar").is_err());
+        assert_eq!(ArchiveFormat::from_path(Path::new("out/Backup.ZIP")), ArchiveFormat::Zip);
+        assert_eq!(ArchiveFormat::from_path(Path::new("backup.tar.zst")), ArchiveFormat::TarZstd);
+        assert_eq!(ArchiveFormat::from_path(Path::new("backup")), ArchiveFormat::Tar);
+    }
+}
@@ -0,0 +1,430 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+
+pub const IGNORE_FILE_NAME: &str = ".quickyignore";
+
+const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+#[derive(Debug)]
+pub enum FilterError {
+    InvalidPattern(String),
+    ExcludeFileReadError(PathBuf),
+}
+
+/// Which files are left out of a backup.
+#[derive(Debug, Default, Clone)]
+pub struct ExcludeOptions {
+    /// Gitignore style patterns, the ones without a leading `/` match at any depth
+    pub excludes: Vec<String>,
+    /// Like `excludes`, but matched case-insensitively
+    pub iexcludes: Vec<String>,
+    /// Files with one exclude pattern per line
+    pub exclude_files: Vec<PathBuf>,
+    /// Patterns of files that are backed up even if an exclude pattern or ignore file matches them
+    pub includes: Vec<String>,
+    /// Skip the contents of directories tagged with a `CACHEDIR.TAG`
+    pub exclude_caches: bool,
+    pub exclude_larger_than: Option<u64>,
+    /// Don't descend into directories on other file systems than the one of the backed up path
+    pub one_file_system: bool,
+}
+
+/// Walks backup paths, leaving out what the exclude options and `.quickyignore` files exclude.
+/// Ignore files use gitignore semantics relative to their directory and apply to its whole subtree.
+pub struct FileFilter {
+    excludes: Gitignore,
+    includes: Gitignore,
+    options: ExcludeOptions,
+}
+
+impl Default for FileFilter {
+    fn default() -> Self {
+        FileFilter {
+            excludes: Gitignore::empty(),
+            includes: Gitignore::empty(),
+            options: ExcludeOptions::default(),
+        }
+    }
+}
+
+impl FileFilter {
+    pub fn new(options: ExcludeOptions) -> Result<FileFilter, FilterError> {
+        let mut excludes = GitignoreBuilder::new("/");
+
+        for pattern in &options.excludes {
+            add_pattern(&mut excludes, pattern)?;
+        }
+
+        for exclude_file in &options.exclude_files {
+            let content = match fs::read_to_string(exclude_file) {
+                Ok(content) => content,
+                Err(_) => return Err(FilterError::ExcludeFileReadError(exclude_file.clone())),
+            };
+
+            for pattern in content.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+                add_pattern(&mut excludes, pattern)?;
+            }
+        }
+
+        excludes.case_insensitive(true).unwrap();
+
+        for pattern in &options.iexcludes {
+            add_pattern(&mut excludes, pattern)?;
+        }
+
+        let mut includes = GitignoreBuilder::new("/");
+
+        for pattern in &options.includes {
+            add_pattern(&mut includes, pattern)?;
+        }
+
+        let excludes = excludes.build().map_err(|err| FilterError::InvalidPattern(err.to_string()))?;
+        let includes = includes.build().map_err(|err| FilterError::InvalidPattern(err.to_string()))?;
+
+        Ok(FileFilter { excludes, includes, options })
+    }
+
+    /// Expands a backup path into the files below it that are not excluded.
+    pub fn walk(&self, root: &Path) -> Vec<PathBuf> {
+        let mut result = vec![];
+
+        let metadata = match fs::metadata(root) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                println!("Failed to read {}: {}", root.display(), err);
+                return result;
+            }
+        };
+
+        // Patterns are matched against absolute paths, the returned paths keep the form they were given in
+        let absolute_root = fs::canonicalize(root).unwrap_or(root.to_path_buf());
+        let mut ignore_files = vec![];
+
+        for ancestor in absolute_root.ancestors().skip(1).collect::<Vec<_>>().into_iter().rev() {
+            if let Some(ignore_file) = read_ignore_file(ancestor) {
+                ignore_files.push(ignore_file);
+            }
+        }
+
+        if metadata.is_dir() {
+            self.walk_dir(root, &absolute_root, device_id(&metadata), &mut ignore_files, &mut result);
+        } else if metadata.is_file() && !self.is_excluded(&absolute_root, false, metadata.len(), &ignore_files) {
+            result.push(root.to_path_buf());
+        }
+
+        result
+    }
+
+    fn walk_dir(&self, dir: &Path, absolute_dir: &Path, device: Option<u64>, ignore_files: &mut Vec<Gitignore>, result: &mut Vec<PathBuf>) {
+        if self.options.exclude_caches && is_cache_dir(absolute_dir) {
+            println!("Skipping cache directory {}", dir.display());
+            return;
+        }
+
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                println!("Failed to read directory {}: {}", dir.display(), err);
+                return;
+            }
+        };
+
+        let ignore_file = read_ignore_file(absolute_dir);
+        let has_ignore_file = ignore_file.is_some();
+
+        if let Some(ignore_file) = ignore_file {
+            ignore_files.push(ignore_file);
+        }
+
+        let mut entries: Vec<_> = entries.flatten().collect();
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let absolute_path = absolute_dir.join(entry.file_name());
+
+            // Symlinks are followed like before, so their target decides between file and directory
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    println!("Failed to read {}: {}", path.display(), err);
+                    continue;
+                }
+            };
+
+            if self.is_excluded(&absolute_path, metadata.is_dir(), metadata.len(), ignore_files) {
+                continue;
+            }
+
+            if metadata.is_dir() {
+                if self.options.one_file_system && device.is_some() && device_id(&metadata) != device {
+                    println!("Skipping {}, it is on another file system", path.display());
+                    continue;
+                }
+
+                self.walk_dir(&path, &absolute_path, device, ignore_files, result);
+            } else if metadata.is_file() {
+                result.push(path);
+            }
+        }
+
+        if has_ignore_file {
+            ignore_files.pop();
+        }
+    }
+
+    fn is_excluded(&self, path: &Path, is_dir: bool, size: u64, ignore_files: &[Gitignore]) -> bool {
+        if self.includes.matched(path, is_dir).is_ignore() {
+            return false;
+        }
+
+        if !is_dir && self.options.exclude_larger_than.is_some_and(|limit| size > limit) {
+            return true;
+        }
+
+        if self.excludes.matched(path, is_dir).is_ignore() {
+            return true;
+        }
+
+        // The deepest ignore file with a matching pattern decides
+        for ignore_file in ignore_files.iter().rev() {
+            match ignore_file.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {},
+            }
+        }
+
+        false
+    }
+}
+
+/// Picks the files of a snapshot to restore: the files below one of `paths` that match one of the
+/// include patterns and none of the exclude patterns. Empty paths or includes select everything.
+pub struct RestoreFilter {
+    paths: Vec<PathBuf>,
+    includes: Gitignore,
+    excludes: Gitignore,
+}
+
+impl Default for RestoreFilter {
+    fn default() -> Self {
+        RestoreFilter {
+            paths: vec![],
+            includes: Gitignore::empty(),
+            excludes: Gitignore::empty(),
+        }
+    }
+}
+
+impl RestoreFilter {
+    pub fn new(paths: Vec<PathBuf>, includes: &[String], excludes: &[String]) -> Result<RestoreFilter, FilterError> {
+        let mut include_builder = GitignoreBuilder::new("/");
+        let mut exclude_builder = GitignoreBuilder::new("/");
+
+        for pattern in includes {
+            add_pattern(&mut include_builder, pattern)?;
+        }
+
+        for pattern in excludes {
+            add_pattern(&mut exclude_builder, pattern)?;
+        }
+
+        Ok(RestoreFilter {
+            paths,
+            includes: include_builder.build().map_err(|err| FilterError::InvalidPattern(err.to_string()))?,
+            excludes: exclude_builder.build().map_err(|err| FilterError::InvalidPattern(err.to_string()))?,
+        })
+    }
+
+    /// Patterns match the path the file was backed up from, including its parent directories. Paths
+    /// may be given as backed up or relative to the restore target, like `docs/report.txt`.
+    pub fn matches(&self, file_path: &Path, restore_path: &Path) -> bool {
+        if !self.paths.is_empty() && !self.paths.iter().any(|path| file_path.starts_with(path) || restore_path.starts_with(path)) {
+            return false;
+        }
+
+        if !self.includes.is_empty() && !self.includes.matched_path_or_any_parents(file_path, false).is_ignore() {
+            return false;
+        }
+
+        !self.excludes.matched_path_or_any_parents(file_path, false).is_ignore()
+    }
+}
+
+/// Patterns without a leading slash match at any depth, also when they contain a slash like `.git/objects`.
+fn add_pattern(builder: &mut GitignoreBuilder, pattern: &str) -> Result<(), FilterError> {
+    let (negation, pattern) = match pattern.strip_prefix('!') {
+        Some(pattern) => ("!", pattern),
+        None => ("", pattern),
+    };
+
+    let line = if pattern.starts_with('/') || pattern.starts_with("**/") {
+        format!("{}{}", negation, pattern)
+    } else {
+        format!("{}**/{}", negation, pattern)
+    };
+
+    match builder.add_line(None, &line) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(FilterError::InvalidPattern(err.to_string())),
+    }
+}
+
+fn read_ignore_file(dir: &Path) -> Option<Gitignore> {
+    let ignore_path = dir.join(IGNORE_FILE_NAME);
+
+    if !ignore_path.is_file() {
+        return None;
+    }
+
+    let (ignore_file, err) = Gitignore::new(&ignore_path);
+
+    if let Some(err) = err {
+        println!("Invalid pattern in {}: {}", ignore_path.display(), err);
+    }
+
+    Some(ignore_file)
+}
+
+fn is_cache_dir(dir: &Path) -> bool {
+    match fs::read(dir.join(CACHEDIR_TAG)) {
+        Ok(content) => content.starts_with(CACHEDIR_TAG_SIGNATURE),
+        Err(_) => false,
+    }
+}
+
+#[cfg(unix)]
+fn device_id(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Parses sizes like `500`, `64K`, `10M` or `1.5G`, using powers of 1024.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        "T" | "TB" | "TIB" => 1024 * 1024 * 1024 * 1024,
+        _ => return Err(format!("unknown size unit {}", unit)),
+    };
+
+    match number.trim().parse::<f64>() {
+        Ok(number) if number >= 0.0 => Ok((number * multiplier as f64) as u64),
+        _ => Err(format!("invalid size {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_tree(files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("quicky_filter_{}", uuid::Uuid::new_v4()));
+
+        for (path, content) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+
+        root
+    }
+
+    fn walk_names(filter: &FileFilter, root: &Path) -> Vec<String> {
+        filter.walk(root).iter()
+            .map(|path| path.strip_prefix(root).unwrap().to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn excludes_and_ignore_files() {
+        let root = create_tree(&[
+            ("main.rs", "fn main() {}"),
+            ("debug.LOG", "log"),
+            ("keep.log", "log"),
+            ("target/app", "binary"),
+            ("repo/.git/objects/ab", "object"),
+            ("repo/.git/HEAD", "ref"),
+            ("web/.quickyignore", "node_modules/\n*.tmp\n!keep.tmp\n"),
+            ("web/node_modules/lib.js", "js"),
+            ("web/a.tmp", "tmp"),
+            ("web/keep.tmp", "tmp"),
+            ("cache/CACHEDIR.TAG", "Signature: 8a477f597d28d172789f06886806bc55"),
+            ("cache/entry", "cached"),
+            ("big.bin", &"0123456789".repeat(10)),
+        ]);
+
+        let filter = FileFilter::new(ExcludeOptions {
+            excludes: vec!["target/".to_string(), ".git/objects".to_string()],
+            iexcludes: vec!["*.log".to_string()],
+            includes: vec!["keep.log".to_string()],
+            exclude_caches: true,
+            exclude_larger_than: Some(99),
+            ..Default::default()
+        }).unwrap();
+
+        let names = walk_names(&filter, &root);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(names, vec!["keep.log", "main.rs", "repo/.git/HEAD", "web/.quickyignore", "web/keep.tmp"]);
+    }
+
+    #[test]
+    fn no_filter() {
+        let root = create_tree(&[("a", "a"), ("b/c", "c")]);
+        let names = walk_names(&FileFilter::default(), &root);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(names, vec!["a", "b/c"]);
+    }
+
+    #[test]
+    fn restore_filter() {
+        let everything = RestoreFilter::default();
+        let by_path = RestoreFilter::new(vec![PathBuf::from("/home/me/docs")], &[], &[]).unwrap();
+        let by_restore_path = RestoreFilter::new(vec![PathBuf::from("me/docs")], &[], &[]).unwrap();
+        let patterns = RestoreFilter::new(vec![], &["docs/".to_string(), "*.rs".to_string()], &["*.tmp".to_string()]).unwrap();
+
+        let matches = |filter: &RestoreFilter, file_path: &str| {
+            let restore_path = Path::new(file_path).strip_prefix("/home/").unwrap();
+            filter.matches(Path::new(file_path), restore_path)
+        };
+
+        assert!(matches(&everything, "/home/me/docs/a.txt"));
+        assert!(matches(&by_path, "/home/me/docs/a.txt"));
+        assert!(!matches(&by_path, "/home/me/docs.txt"));
+        assert!(matches(&by_restore_path, "/home/me/docs/sub/a.txt"));
+        assert!(!matches(&by_restore_path, "/home/me/src/main.rs"));
+        assert!(matches(&patterns, "/home/me/docs/sub/a.txt"));
+        assert!(matches(&patterns, "/home/me/src/main.rs"));
+        assert!(!matches(&patterns, "/home/me/docs/a.tmp"));
+        assert!(!matches(&patterns, "/home/me/notes.txt"));
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("1.5G"), Ok(1024 * 1024 * 1024 * 3 / 2));
+        assert_eq!(parse_size("10mb"), Ok(10 * 1024 * 1024));
+        assert!(parse_size("ten").is_err());
+        assert!(parse_size("10X").is_err());
+    }
+}
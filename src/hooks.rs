@@ -0,0 +1,147 @@
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookStage {
+    Pre,
+    Post,
+    Error,
+}
+
+impl HookStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookStage::Pre => "pre",
+            HookStage::Post => "post",
+            HookStage::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HookError {
+    SpawnError(String),
+    Failed(ExitStatus),
+}
+
+/// Shell commands run around a backup. A failing pre hook aborts the backup unless
+/// `continue_on_pre_failure` is set, the error hook runs when the pre hook, opening the vault, the
+/// backup or the post hook failed, with what went wrong in `QUICKY_ERROR`.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    pub pre: Option<String>,
+    pub post: Option<String>,
+    pub on_error: Option<String>,
+    pub continue_on_pre_failure: bool,
+}
+
+/// What a hook learns about the backup, passed as `QUICKY_*` environment variables.
+#[derive(Debug, Default, Clone)]
+pub struct HookContext {
+    pub vault: PathBuf,
+    pub snapshot_id: Option<String>,
+    pub files: usize,
+    pub bytes: u64,
+    pub duration: u64,
+    pub error: Option<String>,
+}
+
+impl HookContext {
+    fn status(&self, stage: HookStage) -> &'static str {
+        match stage {
+            HookStage::Pre => "running",
+            HookStage::Post => "success",
+            HookStage::Error => "failure",
+        }
+    }
+
+    fn environment(&self, stage: HookStage) -> Vec<(&'static str, String)> {
+        let mut environment = vec![
+            ("QUICKY_HOOK", stage.as_str().to_string()),
+            ("QUICKY_STATUS", self.status(stage).to_string()),
+            ("QUICKY_VAULT", self.vault.display().to_string()),
+            ("QUICKY_SNAPSHOT_ID", self.snapshot_id.clone().unwrap_or_default()),
+            ("QUICKY_FILES", self.files.to_string()),
+            ("QUICKY_BYTES", self.bytes.to_string()),
+            ("QUICKY_DURATION", self.duration.to_string()),
+        ];
+
+        if let Some(error) = &self.error {
+            environment.push(("QUICKY_ERROR", error.clone()));
+        }
+
+        environment
+    }
+}
+
+impl Hooks {
+    pub fn command(&self, stage: HookStage) -> Option<&String> {
+        match stage {
+            HookStage::Pre => self.pre.as_ref(),
+            HookStage::Post => self.post.as_ref(),
+            HookStage::Error => self.on_error.as_ref(),
+        }
+    }
+
+    /// Runs the hook of a stage in a shell, if there is one, and waits for it.
+    pub fn run(&self, stage: HookStage, context: &HookContext) -> Result<(), HookError> {
+        let Some(command) = self.command(stage) else {
+            return Ok(());
+        };
+
+        let mut shell = if cfg!(windows) {
+            let mut shell = Command::new("cmd");
+            shell.arg("/C");
+            shell
+        } else {
+            let mut shell = Command::new("sh");
+            shell.arg("-c");
+            shell
+        };
+
+        match shell.arg(command).envs(context.environment(stage)).status() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(HookError::Failed(status)),
+            Err(err) => Err(HookError::SpawnError(err.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn run() {
+        let output = std::env::temp_dir().join(format!("quicky_hooks_{}", uuid::Uuid::new_v4()));
+        let hooks = Hooks {
+            pre: Some("exit 4".to_string()),
+            post: Some(format!("echo $QUICKY_HOOK $QUICKY_STATUS $QUICKY_SNAPSHOT_ID $QUICKY_FILES $QUICKY_BYTES > {}", output.display())),
+            ..Default::default()
+        };
+
+        let context = HookContext {
+            vault: PathBuf::from("/backups/vault"),
+            snapshot_id: Some("aa01".to_string()),
+            files: 3,
+            bytes: 1024,
+            ..Default::default()
+        };
+
+        let pre = hooks.run(HookStage::Pre, &context);
+        let post = hooks.run(HookStage::Post, &context);
+        let error = hooks.run(HookStage::Error, &context);
+        let written = fs::read_to_string(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+
+        assert!(matches!(pre, Err(HookError::Failed(status)) if status.code() == Some(4)));
+        assert!(post.is_ok());
+        assert!(error.is_ok());
+        assert_eq!(written, "post success aa01 3 1024\n");
+    }
+}
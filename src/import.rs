@@ -0,0 +1,297 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+use chrono::NaiveDateTime;
+
+use crate::backup_vault::*;
+
+/// What an archive turned out to be, from its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArchiveKind {
+    Tar,
+    TarGzip,
+    TarZstd,
+    Zip,
+}
+
+impl ArchiveKind {
+    fn detect(magic: &[u8]) -> ArchiveKind {
+        match magic {
+            [0x1f, 0x8b, ..] => ArchiveKind::TarGzip,
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => ArchiveKind::TarZstd,
+            [b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] => ArchiveKind::Zip,
+            _ => ArchiveKind::Tar,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub snapshot_id: String,
+    pub imported: usize,
+    pub skipped: usize,
+}
+
+/// The metadata of a member, which is stored like a backed up file.
+struct Member {
+    path: PathBuf,
+    modified: u64,
+    mode: Option<u32>,
+}
+
+impl BackupVault {
+    /// Stores the regular files of a tar, optionally gzip or zstd compressed, or zip archive as a new
+    /// snapshot, keeping their paths inside the archive, modes and modification times. Content
+    /// already in the vault is not stored again. Without `time` the snapshot gets the time of the
+    /// newest member.
+    pub fn import_archive(&mut self, archive: &Path, time: Option<u64>, options: &BackupOptions) -> Result<ImportReport, BackupError> {
+        println!("Importing {}...", archive.display());
+
+        self.lock()?;
+        let result = self.import_archive_locked(archive, time, options);
+        self.unlock();
+
+        result
+    }
+
+    fn import_archive_locked(&mut self, archive: &Path, time: Option<u64>, options: &BackupOptions) -> Result<ImportReport, BackupError> {
+        let mut reader = match File::open(archive) {
+            Ok(file) => BufReader::new(file),
+            Err(err) => {
+                println!("Failed to open {}: {}", archive.display(), err);
+                return Err(BackupError::VaultFileOpenError);
+            }
+        };
+
+        let kind = match reader.fill_buf() {
+            Ok(magic) => ArchiveKind::detect(magic),
+            Err(err) => {
+                println!("Failed to read {}: {}", archive.display(), err);
+                return Err(BackupError::VaultFileReadError);
+            }
+        };
+
+        let paths = vec![std::fs::canonicalize(archive).unwrap_or(archive.to_path_buf())];
+        let mut snapshot = new_snapshot(paths, options);
+        let mut report = ImportReport::default();
+
+        match kind {
+            ArchiveKind::Tar => self.import_tar(reader, &mut snapshot, &mut report, options)?,
+            ArchiveKind::TarGzip => self.import_tar(flate2::read::MultiGzDecoder::new(reader), &mut snapshot, &mut report, options)?,
+            ArchiveKind::TarZstd => match zstd::Decoder::with_buffer(reader) {
+                Ok(decoder) => self.import_tar(decoder, &mut snapshot, &mut report, options)?,
+                Err(err) => return Err(archive_error(err)),
+            },
+            ArchiveKind::Zip => self.import_zip(reader.into_inner(), &mut snapshot, &mut report, options)?,
+        }
+
+        let newest = snapshot.snapshot_files.iter().filter_map(|vault_file| vault_file.file_modified).max();
+
+        if let Some(time) = time.or(newest) {
+            snapshot.snapshot_time = time.to_string();
+        }
+
+        report.snapshot_id = snapshot.snapshot_id.clone();
+        report.imported = snapshot.snapshot_files.len();
+
+        self.snapshots.push(snapshot);
+        self.save_index()?;
+
+        println!("Imported {} files, skipped {}", report.imported, report.skipped);
+        println!("Snapshot created {}", report.snapshot_id);
+
+        Ok(report)
+    }
+
+    fn import_tar(&mut self, reader: impl Read, snapshot: &mut Snapshot, report: &mut ImportReport, options: &BackupOptions) -> Result<(), BackupError> {
+        let mut archive = tar::Archive::new(reader);
+
+        for entry in archive.entries().map_err(archive_error)? {
+            let mut entry = entry.map_err(archive_error)?;
+            let header = entry.header();
+            let path = entry.path().map_err(archive_error)?.to_path_buf();
+
+            match header.entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => {},
+                tar::EntryType::Directory => continue,
+                // Only file content can be stored in a vault
+                _ => {
+                    println!("Skipped {}, it is not a regular file", path.display());
+                    report.skipped += 1;
+                    continue;
+                }
+            }
+
+            let member = Member {
+                path,
+                modified: header.mtime().unwrap_or(0),
+                mode: header.mode().ok().map(|mode| mode & 0o7777),
+            };
+
+            self.import_member(member, &mut entry, snapshot, report, options)?;
+        }
+
+        Ok(())
+    }
+
+    fn import_zip(&mut self, file: File, snapshot: &mut Snapshot, report: &mut ImportReport, options: &BackupOptions) -> Result<(), BackupError> {
+        let mut archive = zip::ZipArchive::new(BufReader::new(file)).map_err(archive_error)?;
+
+        for index in 0..archive.len() {
+            let entry = archive.by_index(index).map_err(archive_error)?;
+
+            if entry.is_dir() {
+                continue;
+            }
+
+            // Only file content can be stored in a vault
+            if entry.is_symlink() {
+                println!("Skipped {}, it is not a regular file", entry.name());
+                report.skipped += 1;
+                continue;
+            }
+
+            // The DOS time of a zip entry has no time zone, it is taken as UTC like dump writes it
+            let modified = entry.last_modified()
+                .and_then(|time| NaiveDateTime::try_from(time).ok())
+                .map(|time| time.and_utc().timestamp().max(0) as u64);
+
+            let member = Member {
+                path: PathBuf::from(entry.name()),
+                modified: modified.unwrap_or(0),
+                mode: entry.unix_mode().map(|mode| mode & 0o7777).filter(|mode| *mode != 0),
+            };
+
+            self.import_member(member, entry, snapshot, report, options)?;
+        }
+
+        Ok(())
+    }
+
+    fn import_member(&mut self, member: Member, reader: impl Read, snapshot: &mut Snapshot, report: &mut ImportReport, options: &BackupOptions) -> Result<(), BackupError> {
+        let Some(path) = member_path(&member.path) else {
+            println!("Skipped {}, it has no file name or leads outside of the archive", member.path.display());
+            report.skipped += 1;
+            return Ok(());
+        };
+
+        let mut vault_file = self.vault_add_stream(reader, &path, options)?;
+        vault_file.file_modified = Some(member.modified);
+        vault_file.file_mode = member.mode;
+
+        snapshot.snapshot_files.push(vault_file);
+
+        Ok(())
+    }
+}
+
+/// The path of a member relative to the archive, `./docs/../a` is `a` and `/etc/x` is `etc/x`. None
+/// for members without a file name and members like `docs/../../x` that lead outside of the archive,
+/// so they can't point outside of a restore target.
+fn member_path(path: &Path) -> Option<PathBuf> {
+    let mut member_path = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => member_path.push(name),
+            // Popping the previous component, with nothing to pop the member leads outside
+            Component::ParentDir if !member_path.pop() => return None,
+            _ => {},
+        }
+    }
+
+    member_path.file_name().is_some().then_some(member_path)
+}
+
+fn archive_error(err: impl std::fmt::Display) -> BackupError {
+    println!("Failed to read the archive: {}", err);
+    BackupError::VaultFileReadError
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    use super::*;
+    use crate::backup_vault::tests::temp_vault;
+
+    fn tar_archive(files: &[(&str, &str, u32, u64)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+
+        for (path, content, mode, modified) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(*mode);
+            header.set_mtime(*modified);
+            builder.append_data(&mut header, path, content.as_bytes()).unwrap();
+        }
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "link", "docs/a.txt").unwrap();
+
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn import() {
+        let (root, mut vault) = temp_vault("import");
+
+        let tar = tar_archive(&[("./docs/a.txt", "first", 0o640, 1600000000), ("docs/sub/b.txt", "second", 0o755, 1600000100)]);
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(&tar).unwrap();
+        fs::write(root.join("old.tar.gz"), gzip.finish().unwrap()).unwrap();
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        let options = zip::write::SimpleFileOptions::default();
+        // 1600000000 and 1600000200
+        zip.start_file("docs/a.txt", options.last_modified_time(zip::DateTime::from_date_and_time(2020, 9, 13, 12, 26, 40).unwrap()).unix_permissions(0o600)).unwrap();
+        zip.write_all(b"first").unwrap();
+        zip.start_file("new.txt", options.last_modified_time(zip::DateTime::from_date_and_time(2020, 9, 13, 12, 30, 0).unwrap())).unwrap();
+        zip.write_all(b"third").unwrap();
+        fs::write(root.join("old.zip"), zip.finish().unwrap().into_inner()).unwrap();
+
+        let options = BackupOptions::default();
+
+        let from_tar = vault.import_archive(&root.join("old.tar.gz"), None, &options).unwrap();
+        let blobs = vault.list_blobs().unwrap().len();
+        let from_zip = vault.import_archive(&root.join("old.zip"), Some(1700000000), &options).unwrap();
+        let deduplicated = vault.list_blobs().unwrap().len() - blobs;
+
+        let mut content = vec![];
+        vault.dump_file(&vault.snapshots[0].snapshot_files[1], &mut content).unwrap();
+
+        let files = |index: usize| -> Vec<(String, u64, Option<u64>, Option<u32>)> {
+            vault.snapshots[index].snapshot_files.iter()
+                .map(|vault_file| (vault_file.file_path.display().to_string(), vault_file.file_size, vault_file.file_modified, vault_file.file_mode))
+                .collect()
+        };
+
+        assert_eq!((from_tar.imported, from_tar.skipped), (2, 1));
+        assert_eq!(files(0), vec![
+            ("docs/a.txt".to_string(), 5, Some(1600000000), Some(0o640)),
+            ("docs/sub/b.txt".to_string(), 6, Some(1600000100), Some(0o755)),
+        ]);
+        assert_eq!(vault.snapshots[0].snapshot_time, "1600000100");
+        assert_eq!(content, b"second");
+
+        assert_eq!((from_zip.imported, from_zip.skipped), (2, 0));
+        assert_eq!(files(1)[0], ("docs/a.txt".to_string(), 5, Some(1600000000), Some(0o600)));
+        assert_eq!(vault.snapshots[1].snapshot_time, "1700000000");
+        assert_eq!(deduplicated, 1);
+    }
+
+    #[test]
+    fn member_paths() {
+        assert_eq!(member_path(Path::new("./docs/../a.txt")), Some(PathBuf::from("a.txt")));
+        assert_eq!(member_path(Path::new("docs/old/../../b/c.txt")), Some(PathBuf::from("b/c.txt")));
+        assert_eq!(member_path(Path::new("/etc/passwd")), Some(PathBuf::from("etc/passwd")));
+        assert_eq!(member_path(Path::new("docs/../../x")), None);
+        assert_eq!(member_path(Path::new("../x")), None);
+        assert_eq!(member_path(Path::new("docs/..")), None);
+        assert_eq!(member_path(Path::new("./")), None);
+    }
+}
@@ -0,0 +1,258 @@
+use std::time::{Duration, Instant};
+
+use sodiumoxide::crypto::pwhash::{argon2i13, argon2id13, scryptsalsa208sha256};
+use serde::{Serialize, Deserialize};
+use zeroize::Zeroizing;
+
+pub const KDF_KEY_BYTES: usize = 32;
+/// Scrypt takes the whole salt, Argon2 the first 16 bytes of it.
+pub const KDF_SALT_BYTES: usize = scryptsalsa208sha256::SALTBYTES;
+
+/// Libsodium refuses smaller Argon2 memory limits, the opslimit minimum depends on the algorithm.
+const MEMLIMIT_MIN: u64 = 8192;
+/// The benchmark doesn't suggest less memory than this, even on slow machines.
+const BENCHMARK_MEMLIMIT_MIN: u64 = 8 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum KdfError {
+    InvalidParams(String),
+    DerivationFailed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KdfAlgorithm {
+    /// The libsodium `pwhash` default, which vaults used before the KDF was configurable
+    Scryptsalsa208sha256,
+    Argon2i13,
+    Argon2id13,
+}
+
+impl KdfAlgorithm {
+    /// Parses `argon2id`, `argon2i` or `scrypt`, with or without the full name.
+    pub fn parse(value: &str) -> Result<KdfAlgorithm, String> {
+        match value {
+            "argon2id" | "argon2id13" => Ok(KdfAlgorithm::Argon2id13),
+            "argon2i" | "argon2i13" => Ok(KdfAlgorithm::Argon2i13),
+            "scrypt" | "scryptsalsa208sha256" => Ok(KdfAlgorithm::Scryptsalsa208sha256),
+            _ => Err("expected argon2id, argon2i or scrypt".to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KdfAlgorithm::Scryptsalsa208sha256 => "scrypt",
+            KdfAlgorithm::Argon2i13 => "argon2i",
+            KdfAlgorithm::Argon2id13 => "argon2id",
+        }
+    }
+
+    fn opslimit_min(&self) -> u64 {
+        match self {
+            KdfAlgorithm::Scryptsalsa208sha256 => 32768,
+            KdfAlgorithm::Argon2i13 => 3,
+            KdfAlgorithm::Argon2id13 => 1,
+        }
+    }
+
+    fn memlimit_min(&self) -> u64 {
+        match self {
+            KdfAlgorithm::Scryptsalsa208sha256 => 16 * 1024 * 1024,
+            _ => MEMLIMIT_MIN,
+        }
+    }
+}
+
+/// The libsodium presets, from fast enough for every unlock to several seconds and up to 1 GiB of memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KdfProfile {
+    Interactive,
+    Moderate,
+    Sensitive,
+}
+
+impl KdfProfile {
+    pub fn parse(value: &str) -> Result<KdfProfile, String> {
+        match value {
+            "interactive" => Ok(KdfProfile::Interactive),
+            "moderate" => Ok(KdfProfile::Moderate),
+            "sensitive" => Ok(KdfProfile::Sensitive),
+            _ => Err("expected interactive, moderate or sensitive".to_string()),
+        }
+    }
+}
+
+/// The password KDF of a key slot, stored with it so that stronger parameters can be chosen per vault.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub algorithm: KdfAlgorithm,
+    pub opslimit: u64,
+    /// In bytes
+    pub memlimit: u64,
+}
+
+impl Default for KdfParams {
+    /// What vaults used before the parameters were configurable.
+    fn default() -> Self {
+        KdfParams::from_profile(KdfAlgorithm::Scryptsalsa208sha256, KdfProfile::Interactive).unwrap()
+    }
+}
+
+impl KdfParams {
+    /// Libsodium has no moderate preset for scrypt.
+    pub fn from_profile(algorithm: KdfAlgorithm, profile: KdfProfile) -> Option<KdfParams> {
+        let (opslimit, memlimit) = match (algorithm, profile) {
+            (KdfAlgorithm::Scryptsalsa208sha256, KdfProfile::Interactive) => (scryptsalsa208sha256::OPSLIMIT_INTERACTIVE.0, scryptsalsa208sha256::MEMLIMIT_INTERACTIVE.0),
+            (KdfAlgorithm::Scryptsalsa208sha256, KdfProfile::Moderate) => return None,
+            (KdfAlgorithm::Scryptsalsa208sha256, KdfProfile::Sensitive) => (scryptsalsa208sha256::OPSLIMIT_SENSITIVE.0, scryptsalsa208sha256::MEMLIMIT_SENSITIVE.0),
+            (KdfAlgorithm::Argon2i13, KdfProfile::Interactive) => (argon2i13::OPSLIMIT_INTERACTIVE.0, argon2i13::MEMLIMIT_INTERACTIVE.0),
+            (KdfAlgorithm::Argon2i13, KdfProfile::Moderate) => (argon2i13::OPSLIMIT_MODERATE.0, argon2i13::MEMLIMIT_MODERATE.0),
+            (KdfAlgorithm::Argon2i13, KdfProfile::Sensitive) => (argon2i13::OPSLIMIT_SENSITIVE.0, argon2i13::MEMLIMIT_SENSITIVE.0),
+            (KdfAlgorithm::Argon2id13, KdfProfile::Interactive) => (argon2id13::OPSLIMIT_INTERACTIVE.0, argon2id13::MEMLIMIT_INTERACTIVE.0),
+            (KdfAlgorithm::Argon2id13, KdfProfile::Moderate) => (argon2id13::OPSLIMIT_MODERATE.0, argon2id13::MEMLIMIT_MODERATE.0),
+            (KdfAlgorithm::Argon2id13, KdfProfile::Sensitive) => (argon2id13::OPSLIMIT_SENSITIVE.0, argon2id13::MEMLIMIT_SENSITIVE.0),
+        };
+
+        Some(KdfParams {
+            algorithm,
+            opslimit: opslimit as u64,
+            memlimit: memlimit as u64,
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), KdfError> {
+        if self.opslimit < self.algorithm.opslimit_min() {
+            return Err(KdfError::InvalidParams(format!("the opslimit must be at least {}", self.algorithm.opslimit_min())));
+        }
+
+        if self.memlimit < self.algorithm.memlimit_min() || usize::try_from(self.memlimit).is_err() {
+            return Err(KdfError::InvalidParams(format!("the memlimit must be at least {} bytes", self.algorithm.memlimit_min())));
+        }
+
+        Ok(())
+    }
+
+    pub fn derive(&self, pass: &[u8], salt: &[u8; KDF_SALT_BYTES]) -> Result<Zeroizing<[u8; KDF_KEY_BYTES]>, KdfError> {
+        self.validate()?;
+        sodiumoxide::init().unwrap();
+
+        let mut key = Zeroizing::new([0; KDF_KEY_BYTES]);
+        let opslimit = self.opslimit as usize;
+        let memlimit = self.memlimit as usize;
+
+        let argon2_salt = &salt[..argon2id13::SALTBYTES];
+
+        let result = match self.algorithm {
+            KdfAlgorithm::Scryptsalsa208sha256 => scryptsalsa208sha256::derive_key(
+                &mut *key, pass, &scryptsalsa208sha256::Salt(*salt), scryptsalsa208sha256::OpsLimit(opslimit), scryptsalsa208sha256::MemLimit(memlimit)
+            ).map(|_| ()),
+            KdfAlgorithm::Argon2i13 => argon2i13::derive_key(
+                &mut *key, pass, &argon2i13::Salt::from_slice(argon2_salt).unwrap(), argon2i13::OpsLimit(opslimit), argon2i13::MemLimit(memlimit)
+            ).map(|_| ()),
+            KdfAlgorithm::Argon2id13 => argon2id13::derive_key(
+                &mut *key, pass, &argon2id13::Salt::from_slice(argon2_salt).unwrap(), argon2id13::OpsLimit(opslimit), argon2id13::MemLimit(memlimit)
+            ).map(|_| ()),
+        };
+
+        match result {
+            Ok(_) => Ok(key),
+            Err(_) => Err(KdfError::DerivationFailed),
+        }
+    }
+
+    /// How long deriving a key takes on this machine.
+    pub fn measure(&self) -> Result<Duration, KdfError> {
+        let start = Instant::now();
+        self.derive(b"benchmark password", &[0; KDF_SALT_BYTES])?;
+
+        Ok(start.elapsed())
+    }
+}
+
+/// Finds parameters whose derivation takes about `target` on this machine. Without a `memlimit` the
+/// memory starts at the sensitive profile and is halved until the minimum opslimit fits the target,
+/// then the opslimit is raised as far as the target allows.
+pub fn benchmark(algorithm: KdfAlgorithm, memlimit: Option<u64>, target: Duration) -> Result<(KdfParams, Duration), KdfError> {
+    let mut params = KdfParams {
+        algorithm,
+        opslimit: algorithm.opslimit_min(),
+        memlimit: memlimit.unwrap_or(KdfParams::from_profile(algorithm, KdfProfile::Sensitive).unwrap().memlimit),
+    };
+
+    params.validate()?;
+
+    let mut elapsed = params.measure()?;
+
+    while memlimit.is_none() && elapsed > target && params.memlimit / 2 >= BENCHMARK_MEMLIMIT_MIN.max(algorithm.memlimit_min()) {
+        params.memlimit /= 2;
+        elapsed = params.measure()?;
+    }
+
+    // The time grows about linearly with the opslimit, so one estimate and one correction are enough
+    let mut candidate = params;
+    let mut candidate_elapsed = elapsed;
+
+    for _ in 0..2 {
+        let estimate = candidate.opslimit as f64 * target.as_secs_f64() / candidate_elapsed.as_secs_f64().max(0.001);
+        candidate.opslimit = estimate as u64;
+
+        if candidate.opslimit <= params.opslimit {
+            break;
+        }
+
+        candidate_elapsed = candidate.measure()?;
+
+        if candidate_elapsed <= target {
+            params = candidate;
+            elapsed = candidate_elapsed;
+            break;
+        }
+    }
+
+    Ok((params, elapsed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn params() {
+        let default = KdfParams::default();
+        assert_eq!(default.algorithm, KdfAlgorithm::Scryptsalsa208sha256);
+        assert_eq!(default.opslimit, 524288);
+        assert_eq!(default.memlimit, 16 * 1024 * 1024);
+
+        let moderate = KdfParams::from_profile(KdfAlgorithm::Argon2id13, KdfProfile::Moderate).unwrap();
+        assert!(moderate.memlimit > KdfParams::from_profile(KdfAlgorithm::Argon2id13, KdfProfile::Interactive).unwrap().memlimit);
+        assert_eq!(KdfParams::from_profile(KdfAlgorithm::Scryptsalsa208sha256, KdfProfile::Moderate), None);
+
+        let too_few_ops = KdfParams { algorithm: KdfAlgorithm::Argon2i13, opslimit: 2, ..moderate };
+        assert!(matches!(too_few_ops.validate(), Err(KdfError::InvalidParams(_))));
+
+        let too_little_memory = KdfParams { memlimit: 1024, ..moderate };
+        assert!(matches!(too_little_memory.derive(b"password", &[0; KDF_SALT_BYTES]), Err(KdfError::InvalidParams(_))));
+
+        assert_eq!(KdfAlgorithm::parse("argon2id"), Ok(KdfAlgorithm::Argon2id13));
+        assert_eq!(KdfProfile::parse("sensitive"), Ok(KdfProfile::Sensitive));
+        assert!(KdfProfile::parse("paranoid").is_err());
+    }
+
+    #[test]
+    fn derive() {
+        let params = KdfParams {
+            algorithm: KdfAlgorithm::Argon2id13,
+            opslimit: 1,
+            memlimit: 1024 * 1024,
+        };
+
+        let key1 = params.derive(b"password", &[1; KDF_SALT_BYTES]).unwrap();
+        let key2 = params.derive(b"password", &[1; KDF_SALT_BYTES]).unwrap();
+        let other_salt = params.derive(b"password", &[2; KDF_SALT_BYTES]).unwrap();
+        let other_algorithm = KdfParams { algorithm: KdfAlgorithm::Argon2i13, opslimit: 3, ..params }.derive(b"password", &[1; KDF_SALT_BYTES]).unwrap();
+
+        assert_eq!(key1, key2);
+        assert_ne!(key1, other_salt);
+        assert_ne!(key1, other_algorithm);
+    }
+}
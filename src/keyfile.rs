@@ -0,0 +1,122 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sodiumoxide::randombytes::randombytes_into;
+use zeroize::Zeroizing;
+
+pub const KEYFILE_BYTES: usize = 32;
+
+#[derive(Debug)]
+pub enum KeyfileError {
+    ReadError(PathBuf),
+    WriteError(PathBuf),
+    InvalidKeyfile(PathBuf),
+}
+
+/// A random 256-bit key that unlocks a vault instead of, or together with, a password.
+/// The key is zeroed when dropped.
+#[derive(Clone)]
+pub struct Keyfile {
+    key: Zeroizing<[u8; KEYFILE_BYTES]>,
+}
+
+impl Keyfile {
+    pub fn generate() -> Keyfile {
+        sodiumoxide::init().unwrap();
+
+        let mut key = Zeroizing::new([0; KEYFILE_BYTES]);
+        randombytes_into(&mut *key);
+
+        Keyfile { key }
+    }
+
+    pub fn from_key(key: [u8; KEYFILE_BYTES]) -> Keyfile {
+        Keyfile { key: Zeroizing::new(key) }
+    }
+
+    pub fn key(&self) -> &[u8; KEYFILE_BYTES] {
+        &self.key
+    }
+
+    /// Reads a keyfile written by `write`, or any file of exactly 32 bytes like one taken from `/dev/urandom`.
+    pub fn read(path: &Path) -> Result<Keyfile, KeyfileError> {
+        let content = match fs::read(path) {
+            Ok(content) => Zeroizing::new(content),
+            Err(_) => return Err(KeyfileError::ReadError(path.to_path_buf())),
+        };
+
+        let mut key = Zeroizing::new([0; KEYFILE_BYTES]);
+
+        if content.len() == KEYFILE_BYTES {
+            key.copy_from_slice(&content);
+            return Ok(Keyfile { key });
+        }
+
+        let decoded = match std::str::from_utf8(&content) {
+            Ok(content) => STANDARD.decode(content.trim()).map(Zeroizing::new),
+            Err(_) => return Err(KeyfileError::InvalidKeyfile(path.to_path_buf())),
+        };
+
+        match decoded {
+            Ok(decoded) if decoded.len() == KEYFILE_BYTES => {
+                key.copy_from_slice(&decoded);
+                Ok(Keyfile { key })
+            },
+            _ => Err(KeyfileError::InvalidKeyfile(path.to_path_buf())),
+        }
+    }
+
+    /// Writes the key base64 encoded to a new file that only the owner can read.
+    pub fn write(&self, path: &Path) -> Result<(), KeyfileError> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = match options.open(path) {
+            Ok(file) => file,
+            Err(_) => return Err(KeyfileError::WriteError(path.to_path_buf())),
+        };
+
+        let encoded = Zeroizing::new(STANDARD.encode(*self.key));
+
+        match writeln!(file, "{}", *encoded) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(KeyfileError::WriteError(path.to_path_buf())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_read() {
+        let path = std::env::temp_dir().join(format!("quicky_keyfile_{}", uuid::Uuid::new_v4()));
+        let keyfile = Keyfile::generate();
+
+        keyfile.write(&path).unwrap();
+        let overwritten = keyfile.write(&path);
+        let read = Keyfile::read(&path).unwrap();
+
+        fs::write(&path, [7; KEYFILE_BYTES]).unwrap();
+        let raw = Keyfile::read(&path).unwrap();
+
+        fs::write(&path, "too short").unwrap();
+        let invalid = Keyfile::read(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(overwritten, Err(KeyfileError::WriteError(_))));
+        assert_eq!(read.key(), keyfile.key());
+        assert_eq!(raw.key(), &[7; KEYFILE_BYTES]);
+        assert!(matches!(invalid, Err(KeyfileError::InvalidKeyfile(_))));
+    }
+}
@@ -3,7 +3,27 @@ use clap::Parser;
 
 mod cli;
 mod crypto;
+mod kdf;
+mod compression;
+mod filter;
+mod password;
+mod keyfile;
+mod backend;
 mod backup_vault;
+mod migration;
+mod snapshot;
+mod diff;
+mod stream;
+mod hooks;
+mod retention;
+mod profile;
+mod restore;
+mod dump;
+mod import;
+#[cfg(any(feature = "mount", test))]
+mod mount;
+mod replication;
+mod server;
 
 use cli::Cli;
 
@@ -0,0 +1,109 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::backup_vault::*;
+use crate::kdf::KdfParams;
+
+#[derive(Debug, Default, Serialize)]
+pub struct MigrationReport {
+    pub from_version: u32,
+    pub key_slot_added: bool,
+    pub blobs_rewritten: usize,
+}
+
+impl BackupVault {
+    /// Upgrades a vault written by an older version to the current format: a key derived directly
+    /// from the password or keyfile is moved into a key slot, the key stored in the index is dropped
+    /// and blobs encrypted with the vault nonce are sealed again with a header. The version is only
+    /// raised at the end, so an interrupted migration can simply be run again.
+    pub fn migrate(&mut self, key: &VaultKey, kdf: &KdfParams) -> Result<MigrationReport, BackupError> {
+        self.lock()?;
+        let result = self.migrate_locked(key, kdf);
+        self.unlock();
+
+        result
+    }
+
+    fn migrate_locked(&mut self, key: &VaultKey, kdf: &KdfParams) -> Result<MigrationReport, BackupError> {
+        let mut report = MigrationReport {
+            from_version: self.config.version,
+            ..Default::default()
+        };
+
+        if self.config.version == VAULT_FORMAT_VERSION {
+            return Ok(report);
+        }
+
+        if self.config.key_slots.is_empty() {
+            let key_slot = new_key_slot(&self.crypto, self.config.key_mode, key, kdf)?;
+
+            self.config.key_slots.push(key_slot);
+            self.config.key_check = Some(self.crypto.key_check());
+            self.config.password_hash = String::new();
+            self.save_config()?;
+
+            println!("Moved the vault key into a key slot");
+            report.key_slot_added = true;
+        }
+
+        for blob in self.list_blobs()? {
+            let blob_path = PathBuf::from(&blob);
+
+            if self.is_legacy_blob(&blob_path)? {
+                let (codec, payload) = self.read_blob_payload(&blob_path)?;
+                self.write_blob_payload(&blob_path, codec, &payload)?;
+                report.blobs_rewritten += 1;
+            }
+        }
+
+        // Rewriting the index drops the key older versions stored in it
+        self.save_index()?;
+
+        self.config.version = VAULT_FORMAT_VERSION;
+        self.config.features = current_features();
+        self.save_config()?;
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use zeroize::Zeroizing;
+
+    use super::*;
+    use crate::backup_vault::tests::{test_key, TempDir};
+    use crate::crypto::CryptoModule;
+
+    #[test]
+    fn migrate_legacy_vault() {
+        let root = TempDir::new("migration");
+        let crypto = CryptoModule::new(b"password12345");
+
+        // A vault as written before key slots, blob headers and versioning
+        fs::write(root.join("vault_config.json"), format!(r#"{{"password_hash":"{}"}}"#, bcrypt::hash("password12345", 4).unwrap())).unwrap();
+        fs::write(root.join("vault.json"), serde_json::json!({"snapshots": [], "files": [], "crypto": crypto.params()}).to_string()).unwrap();
+        fs::write(root.join("0123456789abcdef"), crypto.encrypt(b"legacy blob")).unwrap();
+
+        let key = test_key();
+        let mut vault = BackupVault::open(&root, &key).unwrap();
+        let report = vault.migrate(&key, &KdfParams::default()).unwrap();
+
+        assert_eq!(report.from_version, 0);
+        assert!(report.key_slot_added);
+        assert_eq!(report.blobs_rewritten, 1);
+
+        let vault = BackupVault::open(&root, &key).unwrap();
+        let blob_path = PathBuf::from("0123456789abcdef");
+        let wrong_key = BackupVault::open(&root, &VaultKey::from_password(Zeroizing::new("password54321".to_string())));
+
+        assert_eq!(vault.config.version, VAULT_FORMAT_VERSION);
+        assert!(vault.config.password_hash.is_empty());
+        assert!(!vault.is_legacy_blob(&blob_path).unwrap());
+        assert_eq!(vault.read_blob(&blob_path).unwrap(), b"legacy blob");
+        assert!(matches!(wrong_key, Err(BackupError::VaultWrongPassword)));
+    }
+}
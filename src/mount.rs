@@ -0,0 +1,593 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Component, Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+
+use crate::backup_vault::*;
+use crate::snapshot::snapshot_time;
+
+pub const ROOT_INODE: u64 = 1;
+
+/// Decrypted blobs kept around, so that reading a file in small pieces doesn't decrypt it again for every piece
+const CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeKind {
+    Directory,
+    File,
+    Symlink,
+}
+
+#[derive(Debug)]
+pub struct NodeAttributes {
+    pub kind: NodeKind,
+    pub size: u64,
+    pub modified: u64,
+    pub mode: u32,
+}
+
+#[derive(Debug)]
+enum Node {
+    Directory { parent: u64, children: BTreeMap<String, u64>, modified: u64 },
+    File { snapshot: usize, file: usize },
+    Symlink { target: PathBuf, modified: u64 },
+}
+
+/// The read-only file system view of a vault:
+///
+/// - `/snapshots/<time>/...` the files of each snapshot laid out like a restore would, and `latest`
+/// - `/ids/<snapshot id>` links to the snapshot directories
+/// - `/tags/<tag>/<time>` and `/hosts/<hostname>/<time>` links to the snapshots with that tag or host
+///
+/// Inodes are indices into `nodes` plus one, as FUSE reserves 0 and uses 1 for the root.
+pub struct MountTree {
+    nodes: Vec<Node>,
+}
+
+impl MountTree {
+    pub fn new(snapshots: &[Snapshot]) -> MountTree {
+        let latest = snapshots.iter().map(snapshot_time).max().unwrap_or(0);
+        let mut tree = MountTree {
+            nodes: vec![Node::Directory { parent: ROOT_INODE, children: BTreeMap::new(), modified: latest }],
+        };
+
+        let snapshots_dir = tree.directory(ROOT_INODE, "snapshots", latest);
+        let ids_dir = tree.directory(ROOT_INODE, "ids", latest);
+        let tags_dir = tree.directory(ROOT_INODE, "tags", latest);
+        let hosts_dir = tree.directory(ROOT_INODE, "hosts", latest);
+
+        let mut order: Vec<usize> = (0..snapshots.len()).collect();
+        order.sort_by_key(|&index| snapshot_time(&snapshots[index]));
+
+        for index in order {
+            let snapshot = &snapshots[index];
+            let modified = snapshot_time(snapshot);
+            let name = tree.snapshot_name(snapshots_dir, snapshot);
+            let snapshot_dir = tree.directory(snapshots_dir, &name, modified);
+
+            for (file, vault_file) in snapshot.snapshot_files.iter().enumerate() {
+                tree.add_file(snapshot_dir, &snapshot.restore_path(&vault_file.file_path), modified, Node::File { snapshot: index, file });
+            }
+
+            tree.link(ids_dir, &snapshot.snapshot_id, format!("../snapshots/{}", name), modified);
+
+            for tag in &snapshot.tags {
+                let tag_dir = tree.directory(tags_dir, tag, modified);
+                tree.link(tag_dir, &name, format!("../../snapshots/{}", name), modified);
+            }
+
+            if !snapshot.hostname.is_empty() {
+                let host_dir = tree.directory(hosts_dir, &snapshot.hostname, modified);
+                tree.link(host_dir, &name, format!("../../snapshots/{}", name), modified);
+            }
+
+            // Snapshots are added oldest first, so the last one wins
+            tree.remove_child(snapshots_dir, "latest");
+            tree.link(snapshots_dir, "latest", name, modified);
+        }
+
+        tree
+    }
+
+    /// Names a snapshot by its time, adding the start of its id if another snapshot has the same time.
+    fn snapshot_name(&self, snapshots_dir: u64, snapshot: &Snapshot) -> String {
+        let time = DateTime::<Utc>::from_timestamp(snapshot_time(snapshot) as i64, 0).unwrap_or_default();
+        let name = time.format("%Y-%m-%dT%H:%M:%SZ").to_string();
+
+        match self.child(snapshots_dir, &name) {
+            Some(_) => format!("{}-{}", name, &snapshot.snapshot_id[..8.min(snapshot.snapshot_id.len())]),
+            None => name,
+        }
+    }
+
+    fn add_file(&mut self, snapshot_dir: u64, path: &std::path::Path, modified: u64, node: Node) {
+        let names: Vec<String> = path.components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+                _ => None,
+            })
+            .collect();
+
+        let Some((file_name, dirs)) = names.split_last() else {
+            return;
+        };
+
+        let mut parent = snapshot_dir;
+
+        for dir in dirs {
+            parent = self.directory(parent, dir, modified);
+        }
+
+        // Two backed up roots can end in the same name, only the first is shown
+        if self.child(parent, file_name).is_none() {
+            self.insert(parent, file_name, node);
+        }
+    }
+
+    fn directory(&mut self, parent: u64, name: &str, modified: u64) -> u64 {
+        match self.child(parent, name) {
+            Some(inode) => {
+                if let Some(Node::Directory { modified: dir_modified, .. }) = self.nodes.get_mut(inode as usize - 1) {
+                    *dir_modified = (*dir_modified).max(modified);
+                }
+
+                inode
+            },
+            None => self.insert(parent, name, Node::Directory { parent, children: BTreeMap::new(), modified }),
+        }
+    }
+
+    fn link(&mut self, parent: u64, name: &str, target: String, modified: u64) {
+        if self.child(parent, name).is_none() {
+            self.insert(parent, name, Node::Symlink { target: PathBuf::from(target), modified });
+        }
+    }
+
+    fn insert(&mut self, parent: u64, name: &str, node: Node) -> u64 {
+        self.nodes.push(node);
+        let inode = self.nodes.len() as u64;
+
+        if let Some(Node::Directory { children, .. }) = self.nodes.get_mut(parent as usize - 1) {
+            children.insert(name.to_string(), inode);
+        }
+
+        inode
+    }
+
+    fn remove_child(&mut self, parent: u64, name: &str) {
+        if let Some(Node::Directory { children, .. }) = self.nodes.get_mut(parent as usize - 1) {
+            children.remove(name);
+        }
+    }
+
+    fn node(&self, inode: u64) -> Option<&Node> {
+        self.nodes.get((inode as usize).checked_sub(1)?)
+    }
+
+    pub fn child(&self, parent: u64, name: &str) -> Option<u64> {
+        match self.node(parent)? {
+            Node::Directory { children, .. } => children.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    pub fn parent(&self, inode: u64) -> Option<u64> {
+        match self.node(inode)? {
+            Node::Directory { parent, .. } => Some(*parent),
+            _ => None,
+        }
+    }
+
+    /// The entries of a directory, without `.` and `..`.
+    pub fn read_dir(&self, inode: u64) -> Option<Vec<(u64, String)>> {
+        match self.node(inode)? {
+            Node::Directory { children, .. } => Some(children.iter().map(|(name, inode)| (*inode, name.clone())).collect()),
+            _ => None,
+        }
+    }
+
+    pub fn read_link(&self, inode: u64) -> Option<&PathBuf> {
+        match self.node(inode)? {
+            Node::Symlink { target, .. } => Some(target),
+            _ => None,
+        }
+    }
+
+    pub fn attributes(&self, inode: u64, snapshots: &[Snapshot]) -> Option<NodeAttributes> {
+        let attributes = match self.node(inode)? {
+            Node::Directory { modified, .. } => NodeAttributes { kind: NodeKind::Directory, size: 0, modified: *modified, mode: 0o555 },
+            Node::Symlink { target, modified } => NodeAttributes {
+                kind: NodeKind::Symlink,
+                size: target.as_os_str().len() as u64,
+                modified: *modified,
+                mode: 0o777,
+            },
+            Node::File { snapshot, file } => {
+                let snapshot = &snapshots[*snapshot];
+                let vault_file = &snapshot.snapshot_files[*file];
+
+                NodeAttributes {
+                    kind: NodeKind::File,
+                    size: vault_file.file_size,
+                    modified: vault_file.file_modified.unwrap_or(snapshot_time(snapshot)),
+                    // Read-only, whatever the permissions were
+                    mode: vault_file.file_mode.unwrap_or(0o644) & 0o555,
+                }
+            }
+        };
+
+        Some(attributes)
+    }
+
+    fn vault_file<'a>(&self, inode: u64, snapshots: &'a [Snapshot]) -> Option<&'a VaultFile> {
+        match self.node(inode)? {
+            Node::File { snapshot, file } => Some(&snapshots[*snapshot].snapshot_files[*file]),
+            _ => None,
+        }
+    }
+}
+
+/// Keeps the most recently read blobs up to a total size.
+struct BlobCache {
+    blobs: VecDeque<(PathBuf, Vec<u8>)>,
+    size: usize,
+    limit: usize,
+    /// How many blobs were decrypted, cache misses
+    decrypted: usize,
+}
+
+impl BlobCache {
+    fn new(limit: usize) -> BlobCache {
+        BlobCache { blobs: VecDeque::new(), size: 0, limit, decrypted: 0 }
+    }
+
+    fn get(&mut self, vault: &BackupVault, vault_path: &Path) -> Result<&[u8], BackupError> {
+        match self.blobs.iter().position(|(path, _)| path == vault_path) {
+            Some(index) => {
+                let blob = self.blobs.remove(index).unwrap();
+                self.blobs.push_back(blob);
+            },
+            None => {
+                let blob = vault.read_blob(vault_path)?;
+                self.decrypted += 1;
+                self.size += blob.len();
+                self.blobs.push_back((vault_path.to_path_buf(), blob));
+
+                // The blob just read stays even if it is larger than the limit
+                while self.size > self.limit && self.blobs.len() > 1 {
+                    let (_, evicted) = self.blobs.pop_front().unwrap();
+                    self.size -= evicted.len();
+                }
+            }
+        }
+
+        Ok(&self.blobs.back().unwrap().1)
+    }
+}
+
+/// A vault opened for mounting, file content is decrypted when it is read.
+pub struct MountedVault {
+    vault: BackupVault,
+    tree: MountTree,
+    cache: BlobCache,
+    /// The size of every blob of a file but the last, lowered by the tests
+    blob_size: u64,
+}
+
+impl MountedVault {
+    pub fn new(vault: BackupVault) -> MountedVault {
+        let tree = MountTree::new(&vault.snapshots);
+
+        MountedVault { vault, tree, cache: BlobCache::new(CACHE_SIZE), blob_size: BUF_SIZE as u64 }
+    }
+
+    pub fn tree(&self) -> &MountTree {
+        &self.tree
+    }
+
+    pub fn attributes(&self, inode: u64) -> Option<NodeAttributes> {
+        self.tree.attributes(inode, &self.vault.snapshots)
+    }
+
+    /// Reads up to `size` bytes at `offset`, only decrypting the blobs that are needed and not cached.
+    /// Streams are stored in blobs of `BUF_SIZE` of which only the last is shorter, and files from
+    /// disk in a single blob, so where a blob starts follows from its index.
+    pub fn read(&mut self, inode: u64, offset: u64, size: usize) -> Option<Result<Vec<u8>, BackupError>> {
+        let vault_file = self.tree.vault_file(inode, &self.vault.snapshots)?;
+        let last = vault_file.vault_paths.len().saturating_sub(1);
+        let end = offset.saturating_add(size as u64).min(vault_file.file_size);
+        let mut data = vec![];
+
+        let first = (offset / self.blob_size).min(last as u64) as usize;
+
+        for (index, vault_path) in vault_file.vault_paths.iter().enumerate().skip(first) {
+            let blob_start = index as u64 * self.blob_size;
+            let blob_end = if index == last { vault_file.file_size } else { blob_start + self.blob_size };
+
+            if blob_start >= end {
+                break;
+            }
+
+            let blob = match self.cache.get(&self.vault, vault_path) {
+                Ok(blob) => blob,
+                Err(err) => return Some(Err(err)),
+            };
+
+            // A file that changed while it was backed up can have a blob of another size
+            let from = (offset.saturating_sub(blob_start) as usize).min(blob.len());
+            let to = (end.min(blob_end).saturating_sub(blob_start) as usize).min(blob.len());
+
+            if from < to {
+                data.extend_from_slice(&blob[from..to]);
+            }
+        }
+
+        Some(Ok(data))
+    }
+}
+
+#[cfg(feature = "mount")]
+pub use fuse::mount;
+
+#[cfg(feature = "mount")]
+mod fuse {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    use fuser::{FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen, Request};
+
+    use super::*;
+
+    /// Nothing changes while mounted, so the kernel can cache everything for long.
+    const TTL: Duration = Duration::from_secs(3600);
+
+    /// Mounts the vault read-only and blocks until it is unmounted.
+    pub fn mount(vault: BackupVault, mountpoint: &Path) -> std::io::Result<()> {
+        let options = [MountOption::RO, MountOption::FSName("quicky_backup".to_string())];
+
+        fuser::mount2(MountedVault::new(vault), mountpoint, &options)
+    }
+
+    impl MountedVault {
+        fn file_attr(&self, inode: u64, request: &Request<'_>) -> Option<FileAttr> {
+            let attributes = self.attributes(inode)?;
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(attributes.modified);
+
+            Some(FileAttr {
+                ino: inode,
+                size: attributes.size,
+                blocks: attributes.size.div_ceil(512),
+                atime: time,
+                mtime: time,
+                ctime: time,
+                crtime: time,
+                kind: file_type(attributes.kind),
+                perm: attributes.mode as u16,
+                nlink: if attributes.kind == NodeKind::Directory { 2 } else { 1 },
+                uid: request.uid(),
+                gid: request.gid(),
+                rdev: 0,
+                blksize: 4096,
+                flags: 0,
+            })
+        }
+    }
+
+    fn file_type(kind: NodeKind) -> FileType {
+        match kind {
+            NodeKind::Directory => FileType::Directory,
+            NodeKind::File => FileType::RegularFile,
+            NodeKind::Symlink => FileType::Symlink,
+        }
+    }
+
+    impl Filesystem for MountedVault {
+        fn lookup(&mut self, request: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+            let attr = self.tree.child(parent, &name.to_string_lossy())
+                .and_then(|inode| self.file_attr(inode, request));
+
+            match attr {
+                Some(attr) => reply.entry(&TTL, &attr, 0),
+                None => reply.error(libc::ENOENT),
+            }
+        }
+
+        fn getattr(&mut self, request: &Request<'_>, inode: u64, reply: ReplyAttr) {
+            match self.file_attr(inode, request) {
+                Some(attr) => reply.attr(&TTL, &attr),
+                None => reply.error(libc::ENOENT),
+            }
+        }
+
+        fn readlink(&mut self, _request: &Request<'_>, inode: u64, reply: ReplyData) {
+            match self.tree.read_link(inode) {
+                Some(target) => reply.data(target.as_os_str().as_bytes()),
+                None => reply.error(libc::EINVAL),
+            }
+        }
+
+        fn open(&mut self, _request: &Request<'_>, inode: u64, flags: i32, reply: ReplyOpen) {
+            if flags & libc::O_ACCMODE != libc::O_RDONLY {
+                return reply.error(libc::EROFS);
+            }
+
+            match self.attributes(inode) {
+                Some(attributes) if attributes.kind == NodeKind::File => reply.opened(0, 0),
+                Some(_) => reply.error(libc::EISDIR),
+                None => reply.error(libc::ENOENT),
+            }
+        }
+
+        fn read(&mut self, _request: &Request<'_>, inode: u64, _fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
+            match MountedVault::read(self, inode, offset.max(0) as u64, size as usize) {
+                Some(Ok(data)) => reply.data(&data),
+                Some(Err(err)) => {
+                    eprintln!("Failed to read a blob: {}", err);
+                    reply.error(libc::EIO);
+                },
+                None => reply.error(libc::ENOENT),
+            }
+        }
+
+        fn readdir(&mut self, _request: &Request<'_>, inode: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+            let (Some(entries), Some(parent)) = (self.tree.read_dir(inode), self.tree.parent(inode)) else {
+                return reply.error(libc::ENOTDIR);
+            };
+
+            let entries = [(inode, FileType::Directory, ".".to_string()), (parent, FileType::Directory, "..".to_string())].into_iter()
+                .chain(entries.into_iter().map(|(child, name)| {
+                    let kind = self.attributes(child).map(|attributes| file_type(attributes.kind)).unwrap_or(FileType::RegularFile);
+                    (child, kind, name)
+                }));
+
+            // The offset passed back is the one of the next entry
+            for (index, (child, kind, name)) in entries.enumerate().skip(offset.max(0) as usize) {
+                if reply.add(child, index as i64 + 1, kind, name) {
+                    break;
+                }
+            }
+
+            reply.ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::backup_vault::tests::temp_vault;
+
+    fn snapshot(snapshot_id: &str, time: u64, files: &[&str], tags: &[&str]) -> Snapshot {
+        Snapshot {
+            snapshot_id: snapshot_id.to_string(),
+            snapshot_time: time.to_string(),
+            snapshot_files: files.iter()
+                .map(|path| VaultFile {
+                    file_name: path.rsplit('/').next().unwrap().to_string(),
+                    file_hash: String::new(),
+                    file_path: PathBuf::from(path),
+                    file_size: 3,
+                    vault_paths: vec![],
+                    file_modified: None,
+                    file_mode: Some(0o640),
+                })
+                .collect(),
+            hostname: "desk".to_string(),
+            username: String::new(),
+            paths: vec![PathBuf::from("/home/me/docs")],
+            program_version: String::new(),
+            description: None,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    fn resolve(tree: &MountTree, path: &str) -> Option<u64> {
+        path.split('/').filter(|name| !name.is_empty()).try_fold(ROOT_INODE, |inode, name| tree.child(inode, name))
+    }
+
+    fn names(tree: &MountTree, path: &str) -> Vec<String> {
+        tree.read_dir(resolve(tree, path).unwrap()).unwrap().into_iter().map(|(_, name)| name).collect()
+    }
+
+    #[test]
+    fn tree() {
+        // 2024-01-01 00:00:00 UTC and a day later, twice
+        let snapshots = vec![
+            snapshot("bbbbbbbb-2", 1704153600, &["/home/me/docs/new.txt"], &[]),
+            snapshot("aaaaaaaa-1", 1704067200, &["/home/me/docs/a.txt", "/home/me/docs/sub/b.txt"], &["daily"]),
+            snapshot("cccccccc-3", 1704153600, &["/home/me/docs/a.txt"], &["daily"]),
+        ];
+
+        let tree = MountTree::new(&snapshots);
+        let file = resolve(&tree, "snapshots/2024-01-01T00:00:00Z/docs/sub/b.txt").unwrap();
+        let attributes = tree.attributes(file, &snapshots).unwrap();
+        let link = |path: &str| tree.read_link(resolve(&tree, path).unwrap()).unwrap().display().to_string();
+
+        assert_eq!(names(&tree, ""), vec!["hosts", "ids", "snapshots", "tags"]);
+        assert_eq!(names(&tree, "snapshots"), vec!["2024-01-01T00:00:00Z", "2024-01-02T00:00:00Z", "2024-01-02T00:00:00Z-cccccccc", "latest"]);
+        assert_eq!(names(&tree, "snapshots/2024-01-01T00:00:00Z/docs"), vec!["a.txt", "sub"]);
+        assert_eq!((attributes.kind, attributes.size, attributes.modified, attributes.mode), (NodeKind::File, 3, 1704067200, 0o440));
+        assert_eq!(link("snapshots/latest"), "2024-01-02T00:00:00Z-cccccccc");
+        assert_eq!(link("ids/aaaaaaaa-1"), "../snapshots/2024-01-01T00:00:00Z");
+        assert_eq!(names(&tree, "tags/daily"), vec!["2024-01-01T00:00:00Z", "2024-01-02T00:00:00Z-cccccccc"]);
+        assert_eq!(link("hosts/desk/2024-01-02T00:00:00Z"), "../../snapshots/2024-01-02T00:00:00Z");
+        assert_eq!(tree.parent(resolve(&tree, "snapshots").unwrap()), Some(ROOT_INODE));
+        assert!(resolve(&tree, "snapshots/2024-01-01T00:00:00Z/docs/new.txt").is_none());
+    }
+
+    #[test]
+    fn read() {
+        let (root, mut vault) = temp_vault("mount");
+        let source = root.join("data");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("file"), "0123456789").unwrap();
+
+        vault.backup(&vec![source.clone()], &BackupOptions::default()).unwrap();
+
+        let mut mounted = MountedVault::new(vault);
+        let latest = resolve(mounted.tree(), "snapshots/latest").and_then(|link| mounted.tree().read_link(link).cloned()).unwrap();
+        let file = resolve(mounted.tree(), &format!("snapshots/{}/data/file", latest.display())).unwrap();
+
+        // A file from disk is one blob, also when it is larger than the blobs of a stream
+        mounted.blob_size = 4;
+        let whole = mounted.read(file, 0, 4096).unwrap().unwrap();
+        let middle = mounted.read(file, 3, 4).unwrap().unwrap();
+        let tail = mounted.read(file, 8, 4).unwrap().unwrap();
+        let past_end = mounted.read(file, 20, 4).unwrap().unwrap();
+        let cached = mounted.cache.blobs.len();
+        let attributes = mounted.attributes(file).unwrap();
+
+        assert_eq!(whole, b"0123456789");
+        assert_eq!(middle, b"3456");
+        assert_eq!(tail, b"89");
+        assert!(past_end.is_empty());
+        assert_eq!(cached, 1);
+        assert_eq!((attributes.kind, attributes.size), (NodeKind::File, 10));
+        assert!(mounted.read(ROOT_INODE, 0, 10).is_none());
+    }
+
+    #[test]
+    fn read_large_file() {
+        let (_root, mut vault) = temp_vault("mount");
+
+        // 20 blobs of 100 bytes and a cache of 3 blobs, like a file of 80 MiB with the 64 MiB cache
+        let mut content = vec![];
+        let mut vault_paths = vec![];
+
+        for index in 0..20u8 {
+            let blob = vec![index; 100];
+            let vault_path = PathBuf::from(format!("{:016x}", index));
+            vault.write_blob(&vault_path, &blob, "blob", crate::compression::Compression::Off).unwrap();
+            content.extend(blob);
+            vault_paths.push(vault_path);
+        }
+
+        let mut large = snapshot("dddddddd-4", 1704067200, &["/home/me/docs/large.bin"], &[]);
+        large.snapshot_files[0].file_size = content.len() as u64;
+        large.snapshot_files[0].vault_paths = vault_paths;
+        vault.snapshots.push(large);
+
+        let mut mounted = MountedVault::new(vault);
+        mounted.cache = BlobCache::new(300);
+        mounted.blob_size = 100;
+        let file = resolve(mounted.tree(), "snapshots/2024-01-01T00:00:00Z/docs/large.bin").unwrap();
+
+        let high = mounted.read(file, 1850, 100).unwrap().unwrap();
+        let after_high = mounted.cache.decrypted;
+        let across = mounted.read(file, 1050, 100).unwrap().unwrap();
+        let after_across = mounted.cache.decrypted;
+        let again = mounted.read(file, 1900, 100).unwrap().unwrap();
+        let after_again = mounted.cache.decrypted;
+
+        assert_eq!(high, content[1850..1950]);
+        assert_eq!(after_high, 2);
+        assert_eq!(across, content[1050..1150]);
+        assert_eq!(after_across, 4);
+        assert_eq!(again, content[1900..2000]);
+        assert_eq!(after_again, 4);
+    }
+}
@@ -0,0 +1,166 @@
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use zeroize::Zeroizing;
+
+pub const PASSWORD_ENV: &str = "QUICKY_PASSWORD";
+
+#[derive(Debug)]
+pub enum PasswordError {
+    FileReadError(PathBuf),
+    CommandError(String),
+    PromptError,
+    EmptyPassword,
+}
+
+/// Where the vault password comes from, checked in this order: command, file, `QUICKY_PASSWORD`, prompt.
+#[derive(Debug, Clone, Default)]
+pub struct PasswordSource {
+    pub password_file: Option<PathBuf>,
+    pub password_command: Option<String>,
+}
+
+impl PasswordSource {
+    /// Whether the password is typed in by a user, who can then be asked to confirm it.
+    pub fn is_interactive(&self) -> bool {
+        self.password_command.is_none()
+            && self.password_file.is_none()
+            && std::env::var_os(PASSWORD_ENV).is_none()
+            && std::io::stdin().is_terminal()
+    }
+
+    /// Whether the password would be read as a line from a piped stdin, which then can't carry data.
+    pub fn reads_stdin(&self) -> bool {
+        self.password_command.is_none()
+            && self.password_file.is_none()
+            && std::env::var_os(PASSWORD_ENV).is_none()
+            && !std::io::stdin().is_terminal()
+    }
+
+    /// The password is zeroed when dropped, like everything it was read from.
+    pub fn read(&self, prompt: &str) -> Result<Zeroizing<String>, PasswordError> {
+        let password = if let Some(command) = &self.password_command {
+            run_password_command(command)?
+        } else if let Some(password_file) = &self.password_file {
+            read_password_file(password_file)?
+        } else if let Ok(password) = std::env::var(PASSWORD_ENV) {
+            Zeroizing::new(password)
+        } else {
+            prompt_password(prompt)?
+        };
+
+        if password.is_empty() {
+            return Err(PasswordError::EmptyPassword);
+        }
+
+        Ok(password)
+    }
+}
+
+/// Reads the first line of a password file.
+fn read_password_file(path: &Path) -> Result<Zeroizing<String>, PasswordError> {
+    match fs::read_to_string(path).map(Zeroizing::new) {
+        Ok(content) => Ok(first_line(&content)),
+        Err(_) => Err(PasswordError::FileReadError(path.to_path_buf())),
+    }
+}
+
+/// Runs a command like `pass show backup` through the shell and takes the first line of its output.
+/// The command gets no stdin, so it can't eat the data of a backup that reads stdin.
+fn run_password_command(command: &str) -> Result<Zeroizing<String>, PasswordError> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+
+    let output = shell.arg(command)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => Ok(first_line(&Zeroizing::new(String::from_utf8(output.stdout).unwrap_or_default()))),
+        Ok(output) => Err(PasswordError::CommandError(format!("password command exited with {}", output.status))),
+        Err(err) => Err(PasswordError::CommandError(err.to_string())),
+    }
+}
+
+/// Prompts without echo on a terminal, otherwise reads a line from stdin.
+pub fn prompt_password(prompt: &str) -> Result<Zeroizing<String>, PasswordError> {
+    if std::io::stdin().is_terminal() {
+        return match rpassword::prompt_password(prompt) {
+            Ok(password) => Ok(Zeroizing::new(password)),
+            Err(_) => Err(PasswordError::PromptError),
+        };
+    }
+
+    // On stderr like the terminal prompt, so that the output of dump stays clean
+    eprint!("{}", prompt);
+    std::io::stderr().flush().unwrap();
+
+    let mut password = Zeroizing::new(String::new());
+
+    match std::io::stdin().read_line(&mut password) {
+        Ok(_) => Ok(first_line(&password)),
+        Err(_) => Err(PasswordError::PromptError),
+    }
+}
+
+fn first_line(content: &str) -> Zeroizing<String> {
+    Zeroizing::new(content.lines().next().unwrap_or("").trim_end_matches('\r').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_file() {
+        let path = std::env::temp_dir().join(format!("quicky_password_{}", uuid::Uuid::new_v4()));
+        fs::write(&path, "  secret passphrase \r\nsecond line\n").unwrap();
+
+        let source = PasswordSource {
+            password_file: Some(path.clone()),
+            ..Default::default()
+        };
+
+        let password = source.read("");
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(*password.unwrap(), "  secret passphrase ");
+        assert!(!source.is_interactive());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn password_command() {
+        let source = PasswordSource {
+            password_command: Some("echo from-command; echo ignored".to_string()),
+            password_file: Some(PathBuf::from("/nonexistent")),
+        };
+
+        assert_eq!(*source.read("").unwrap(), "from-command");
+
+        // The command must not read the stdin of the backup
+        let reading_stdin = PasswordSource {
+            password_command: Some("cat; echo after-stdin".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(*reading_stdin.read("").unwrap(), "after-stdin");
+
+        let failing = PasswordSource {
+            password_command: Some("exit 3".to_string()),
+            ..Default::default()
+        };
+
+        assert!(matches!(failing.read(""), Err(PasswordError::CommandError(_))));
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::compression::Compression;
+use crate::filter::{parse_size, ExcludeOptions};
+use crate::hooks::Hooks;
+use crate::retention::RetentionPolicy;
+use crate::snapshot::{normalize_tags, SnapshotFilter};
+
+#[derive(Debug)]
+pub enum ProfileError {
+    NoConfigPath,
+    ReadError(PathBuf, String),
+    ParseError(PathBuf, String),
+    UnknownJob(String, Vec<String>),
+    InvalidJob(String, String),
+}
+
+/// The config file, named backup jobs as `[jobs.NAME]` tables:
+///
+/// ```toml
+/// [jobs.home]
+/// vault = "/mnt/backup/vault"
+/// sources = ["~/documents", "~/projects"]
+/// excludes = ["target/", "*.tmp"]
+/// tags = ["home"]
+/// password_command = "pass show backup"
+///
+/// [jobs.home.hooks]
+/// post = "notify-send 'Backup done'"
+///
+/// [jobs.home.retention]
+/// keep_daily = 7
+/// keep_weekly = 4
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub jobs: BTreeMap<String, Job>,
+}
+
+/// A backup job with the options of the backup command. Paths starting with `~` are relative to the
+/// home directory, other relative paths to the directory of the config file.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Job {
+    pub vault: PathBuf,
+    /// Create the vault on the first run
+    pub init: bool,
+    pub sources: Vec<PathBuf>,
+    pub excludes: Vec<String>,
+    pub iexcludes: Vec<String>,
+    pub exclude_files: Vec<PathBuf>,
+    pub includes: Vec<String>,
+    pub exclude_caches: bool,
+    /// A size like `10M`
+    pub exclude_larger_than: Option<String>,
+    pub one_file_system: bool,
+    /// `off`, `auto` or a zstd level, `auto` if not set
+    pub compression: Option<String>,
+    pub tags: Vec<String>,
+    pub description: Option<String>,
+    /// Back up the output of this command and its arguments instead of `sources`
+    pub stdin_from_command: Vec<String>,
+    pub stdin_filename: Option<PathBuf>,
+    pub password_file: Option<PathBuf>,
+    pub password_command: Option<String>,
+    pub keyfile: Option<PathBuf>,
+    pub with_password: bool,
+    pub hooks: Hooks,
+    /// Which snapshots of the job `run` keeps after backing up
+    pub retention: Option<RetentionPolicy>,
+}
+
+impl Config {
+    /// The config file to use when none is given: `quicky_backup/config.toml` in `$XDG_CONFIG_HOME`,
+    /// `~/.config` or on Windows `%APPDATA%`.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None if cfg!(windows) => PathBuf::from(std::env::var_os("APPDATA")?),
+            None => home_dir()?.join(".config"),
+        };
+
+        Some(config_dir.join("quicky_backup").join("config.toml"))
+    }
+
+    pub fn load(path: &Path) -> Result<Config, ProfileError> {
+        let content = fs::read_to_string(path).map_err(|err| ProfileError::ReadError(path.to_path_buf(), err.to_string()))?;
+        let mut config: Config = toml::from_str(&content).map_err(|err| ProfileError::ParseError(path.to_path_buf(), err.to_string()))?;
+
+        let base = path.parent().unwrap_or(Path::new("."));
+
+        for job in config.jobs.values_mut() {
+            job.resolve_paths(base);
+        }
+
+        Ok(config)
+    }
+
+    /// A job by its name, checked for options the backup command would reject.
+    pub fn job(&self, name: &str) -> Result<&Job, ProfileError> {
+        let Some(job) = self.jobs.get(name) else {
+            return Err(ProfileError::UnknownJob(name.to_string(), self.jobs.keys().cloned().collect()));
+        };
+
+        let invalid = |message: String| Err(ProfileError::InvalidJob(name.to_string(), message));
+
+        if job.vault.as_os_str().is_empty() {
+            return invalid("it has no vault".to_string());
+        }
+
+        if job.sources.is_empty() && job.stdin_from_command.is_empty() {
+            return invalid("it has neither sources nor stdin_from_command".to_string());
+        }
+
+        if !job.sources.is_empty() && !job.stdin_from_command.is_empty() {
+            return invalid("sources and stdin_from_command can't be used together".to_string());
+        }
+
+        if let Err(err) = job.compression() {
+            return invalid(err);
+        }
+
+        if let Err(err) = job.exclude_options() {
+            return invalid(err);
+        }
+
+        Ok(job)
+    }
+}
+
+impl Job {
+    pub fn compression(&self) -> Result<Option<Compression>, String> {
+        self.compression.as_deref().map(Compression::parse).transpose()
+    }
+
+    pub fn exclude_options(&self) -> Result<ExcludeOptions, String> {
+        Ok(ExcludeOptions {
+            excludes: self.excludes.clone(),
+            iexcludes: self.iexcludes.clone(),
+            exclude_files: self.exclude_files.clone(),
+            includes: self.includes.clone(),
+            exclude_caches: self.exclude_caches,
+            exclude_larger_than: self.exclude_larger_than.as_deref().map(parse_size).transpose()?,
+            one_file_system: self.one_file_system,
+        })
+    }
+
+    /// The snapshots this job took on this host, which its retention policy applies to.
+    pub fn snapshot_filter(&self) -> SnapshotFilter {
+        let paths = match &self.stdin_filename {
+            Some(stdin_filename) if self.sources.is_empty() => vec![stdin_filename.clone()],
+            None if self.sources.is_empty() => vec![PathBuf::from("stdin")],
+            _ => self.sources.clone(),
+        };
+
+        SnapshotFilter {
+            hosts: vec![sysinfo::System::host_name().unwrap_or_default()],
+            tags: normalize_tags(&self.tags),
+            paths,
+        }
+    }
+
+    fn resolve_paths(&mut self, base: &Path) {
+        self.vault = resolve_path(&self.vault, base);
+        self.sources = self.sources.iter().map(|source| resolve_path(source, base)).collect();
+        self.exclude_files = self.exclude_files.iter().map(|exclude_file| resolve_path(exclude_file, base)).collect();
+        self.password_file = self.password_file.as_ref().map(|password_file| resolve_path(password_file, base));
+        self.keyfile = self.keyfile.as_ref().map(|keyfile| resolve_path(keyfile, base));
+    }
+}
+
+fn resolve_path(path: &Path, base: &Path) -> PathBuf {
+    if path.as_os_str().is_empty() {
+        return path.to_path_buf();
+    }
+
+    match path.strip_prefix("~") {
+        Ok(relative) => match home_dir() {
+            Some(home) => home.join(relative),
+            None => path.to_path_buf(),
+        },
+        // Joining keeps absolute paths as they are
+        Err(_) => base.join(path),
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")).filter(|home| !home.is_empty()).map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load() {
+        let root = std::env::temp_dir().join(format!("quicky_profile_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+
+        let path = root.join("config.toml");
+        fs::write(&path, r#"
+            [jobs.home]
+            vault = "vault"
+            sources = ["/srv/data", "~/documents"]
+            exclude_larger_than = "1M"
+            compression = "5"
+            tags = [" nightly", "nightly"]
+
+            [jobs.home.hooks]
+            pre = "true"
+
+            [jobs.home.retention]
+            keep_last = 3
+
+            [jobs.empty]
+            vault = "/backup"
+        "#).unwrap();
+
+        let config = Config::load(&path).unwrap();
+        let unknown_field = fs::write(&path, "[jobs.home]\nvaul = \"x\"\n").map(|_| Config::load(&path));
+        fs::remove_dir_all(&root).unwrap();
+
+        let job = config.job("home").unwrap();
+        let home = home_dir().unwrap();
+
+        assert_eq!(job.vault, root.join("vault"));
+        assert_eq!(job.sources, vec![PathBuf::from("/srv/data"), home.join("documents")]);
+        assert_eq!(job.exclude_options().unwrap().exclude_larger_than, Some(1024 * 1024));
+        assert!(matches!(job.compression(), Ok(Some(Compression::Level(5)))));
+        assert_eq!(job.hooks.pre.as_deref(), Some("true"));
+        assert_eq!(job.retention.as_ref().unwrap().keep_last, 3);
+        assert_eq!(job.snapshot_filter().tags, vec!["nightly"]);
+
+        assert!(matches!(config.job("empty"), Err(ProfileError::InvalidJob(..))));
+        assert!(matches!(config.job("work"), Err(ProfileError::UnknownJob(_, jobs)) if jobs == vec!["empty", "home"]));
+        assert!(matches!(unknown_field, Ok(Err(ProfileError::ParseError(..)))));
+    }
+}
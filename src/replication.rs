@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::backup_vault::*;
+use crate::snapshot::{SelectorError, SnapshotSelector};
+
+#[derive(Debug, Default, Serialize)]
+pub struct CopyReport {
+    pub snapshots_copied: usize,
+    pub snapshots_skipped: usize,
+    pub blobs_copied: usize,
+    pub blobs_skipped: usize,
+    pub bytes_copied: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct MirrorReport {
+    pub snapshots_added: Vec<String>,
+    /// Snapshots whose metadata, like tags or the description, changed since they were mirrored
+    pub snapshots_updated: Vec<String>,
+    pub snapshots_removed: Vec<String>,
+    pub blobs_copied: usize,
+    pub bytes_copied: u64,
+    pub blobs_deleted: usize,
+    pub blobs_verified: usize,
+    /// Blobs that were missing or damaged in the destination and copied again
+    pub blobs_repaired: usize,
+    pub verify_errors: Vec<String>,
+}
+
+/// Picks the snapshots to copy: the selected ones, or the `latest` most recent ones, or all of them.
+pub fn select_snapshots(vault: &BackupVault, selectors: &[SnapshotSelector], latest: Option<usize>) -> Result<Vec<String>, SelectorError> {
+    let mut selected: Vec<String> = vec![];
+
+    for selector in selectors {
+        let snapshot_id = &selector.find(&vault.snapshots)?.snapshot_id;
+
+        if !selected.contains(snapshot_id) {
+            selected.push(snapshot_id.clone());
+        }
+    }
+
+    if selectors.is_empty() {
+        let skip = match latest {
+            Some(latest) => vault.snapshots.len().saturating_sub(latest),
+            None => 0,
+        };
+
+        selected.extend(vault.snapshots.iter().skip(skip).map(|s| s.snapshot_id.clone()));
+    }
+
+    Ok(selected)
+}
+
+impl BackupVault {
+    /// Copies snapshots into `destination`, keeping their ids. Blobs are re-encrypted with the
+    /// destination key and only transferred if the destination doesn't have them yet.
+    pub fn copy_snapshots(&self, destination: &mut BackupVault, snapshot_ids: &[String]) -> Result<CopyReport, BackupError> {
+        destination.lock()?;
+        let result = self.copy_snapshots_locked(destination, snapshot_ids);
+        destination.unlock();
+
+        result
+    }
+
+    fn copy_snapshots_locked(&self, destination: &mut BackupVault, snapshot_ids: &[String]) -> Result<CopyReport, BackupError> {
+        let mut report = CopyReport::default();
+        let mut present_blobs: HashSet<String> = HashSet::new();
+
+        for snapshot_id in snapshot_ids {
+            let snapshot = match self.snapshots.iter().find(|s| s.snapshot_id == *snapshot_id) {
+                Some(snapshot) => snapshot,
+                None => {
+                    println!("Snapshot not found: {}", snapshot_id);
+                    return Err(BackupError::VaultReadError);
+                }
+            };
+
+            if destination.snapshots.iter().any(|s| s.snapshot_id == *snapshot_id) {
+                println!("Snapshot {} already exists in the destination", snapshot_id);
+                report.snapshots_skipped += 1;
+                continue;
+            }
+
+            let snapshot = replica(snapshot);
+
+            for vault_file in &snapshot.snapshot_files {
+                for vault_path in &vault_file.vault_paths {
+                    if present_blobs.contains(&blob_name(vault_path)) || destination.has_blob(vault_path)? {
+                        report.blobs_skipped += 1;
+                    } else {
+                        // The payload stays compressed, it is only re-encrypted for the destination
+                        let (codec, payload) = self.read_blob_payload(vault_path)?;
+                        destination.write_blob_payload(vault_path, codec, &payload)?;
+
+                        report.blobs_copied += 1;
+                        report.bytes_copied += payload.len() as u64;
+                    }
+
+                    present_blobs.insert(blob_name(vault_path));
+                }
+            }
+
+            // Keep the destination ordered by time, the last snapshot is the latest one
+            let snapshot_time = snapshot.snapshot_time.parse::<u64>().unwrap_or(0);
+            let position = destination.snapshots.iter()
+                .position(|s| s.snapshot_time.parse::<u64>().unwrap_or(0) > snapshot_time)
+                .unwrap_or(destination.snapshots.len());
+
+            destination.snapshots.insert(position, snapshot);
+            destination.save_index()?;
+
+            println!("Copied snapshot {}", snapshot_id);
+            report.snapshots_copied += 1;
+        }
+
+        Ok(report)
+    }
+
+    /// Makes `destination` an exact replica of this vault: missing snapshots are copied, changed ones
+    /// replaced, snapshots that are not in this vault are removed and blobs no longer referenced are
+    /// deleted. Every referenced blob is checked to exist, and with `verify_data` also decrypted and
+    /// hashed. Blobs failing the check are copied again and checked once more.
+    pub fn mirror(&self, destination: &mut BackupVault, verify_data: bool) -> Result<MirrorReport, BackupError> {
+        destination.lock()?;
+        let result = self.mirror_locked(destination, verify_data);
+        destination.unlock();
+
+        result
+    }
+
+    fn mirror_locked(&self, destination: &mut BackupVault, verify_data: bool) -> Result<MirrorReport, BackupError> {
+        let mut report = MirrorReport::default();
+
+        for snapshot in &self.snapshots {
+            match destination.snapshots.iter().find(|d| d.snapshot_id == snapshot.snapshot_id) {
+                None => report.snapshots_added.push(snapshot.snapshot_id.clone()),
+                Some(mirrored) if *mirrored != replica(snapshot) => report.snapshots_updated.push(snapshot.snapshot_id.clone()),
+                Some(_) => {},
+            }
+        }
+
+        // Changed snapshots are copied again like missing ones, which also brings any new blobs
+        destination.snapshots.retain(|d| !report.snapshots_updated.contains(&d.snapshot_id));
+
+        let copied: Vec<String> = [report.snapshots_added.clone(), report.snapshots_updated.clone()].concat();
+        let copy_report = self.copy_snapshots_locked(destination, &copied)?;
+        report.blobs_copied = copy_report.blobs_copied;
+        report.bytes_copied = copy_report.bytes_copied;
+
+        for snapshot_id in &report.snapshots_updated {
+            println!("Updated snapshot {}", snapshot_id);
+        }
+
+        report.snapshots_removed = destination.snapshots.iter()
+            .filter(|d| !self.snapshots.iter().any(|s| s.snapshot_id == d.snapshot_id))
+            .map(|d| d.snapshot_id.clone())
+            .collect();
+
+        if !report.snapshots_removed.is_empty() {
+            destination.snapshots.retain(|d| self.snapshots.iter().any(|s| s.snapshot_id == d.snapshot_id));
+            destination.save_index()?;
+
+            for snapshot_id in &report.snapshots_removed {
+                println!("Removed snapshot {}", snapshot_id);
+            }
+        }
+
+        let referenced: HashSet<String> = destination.snapshots.iter()
+            .flat_map(|s| s.snapshot_files.iter())
+            .flat_map(|f| f.vault_paths.iter())
+            .map(|vault_path| blob_name(vault_path))
+            .collect();
+
+        for blob in destination.list_blobs()? {
+            if !referenced.contains(&blob) {
+                destination.delete_blob(&PathBuf::from(&blob))?;
+                report.blobs_deleted += 1;
+            }
+        }
+
+        let mut referenced: Vec<String> = referenced.into_iter().collect();
+        referenced.sort();
+
+        for blob in referenced {
+            let blob_path = PathBuf::from(&blob);
+
+            if check_blob(destination, &blob_path, verify_data).is_ok() {
+                report.blobs_verified += 1;
+                continue;
+            }
+
+            println!("Blob {} is missing or damaged in the destination, copying it again", blob);
+
+            let repaired = self.read_blob_payload(&blob_path)
+                .and_then(|(codec, payload)| destination.write_blob_payload(&blob_path, codec, &payload));
+
+            match repaired.and_then(|_| check_blob(destination, &blob_path, verify_data)) {
+                Ok(()) => {
+                    report.blobs_verified += 1;
+                    report.blobs_repaired += 1;
+                },
+                Err(err) => report.verify_errors.push(format!("blob {} is missing or damaged and could not be repaired: {}", blob, err)),
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+/// A snapshot as it is stored in a replica, with its blobs referred to by name only.
+fn replica(snapshot: &Snapshot) -> Snapshot {
+    let mut snapshot = snapshot.clone();
+
+    for vault_file in snapshot.snapshot_files.iter_mut() {
+        for vault_path in vault_file.vault_paths.iter_mut() {
+            *vault_path = PathBuf::from(blob_name(vault_path));
+        }
+    }
+
+    snapshot
+}
+
+/// Checks that a blob exists, and with `verify_data` that it decrypts to its hash.
+fn check_blob(vault: &BackupVault, blob_path: &Path, verify_data: bool) -> Result<(), BackupError> {
+    let is_valid = match verify_data {
+        true => vault.verify_blob(blob_path)?,
+        false => vault.has_blob(blob_path)?,
+    };
+
+    match is_valid {
+        true => Ok(()),
+        false => Err(BackupError::VaultDecryptionError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::backup_vault::tests::{temp_vault, test_key};
+    use crate::snapshot::TagChanges;
+
+    #[test]
+    fn mirror() {
+        let (root, mut source) = temp_vault("replication");
+        let mut destination = BackupVault::create(&root.join("destination"), &test_key(), &crate::kdf::KdfParams::default()).unwrap();
+        fs::create_dir_all(root.join("data")).unwrap();
+        fs::write(root.join("data").join("a.txt"), "first").unwrap();
+        fs::write(root.join("data").join("b.txt"), "second").unwrap();
+
+        let snapshot_id = source.backup(&vec![root.join("data")], &BackupOptions::default()).unwrap();
+        let first = source.mirror(&mut destination, true).unwrap();
+
+        let changes = TagChanges { add: vec!["kept".to_string()], ..Default::default() };
+        source.tag_snapshot(&snapshot_id, &changes).unwrap();
+        let tagged = source.mirror(&mut destination, true).unwrap();
+
+        let lost_blob = destination.list_blobs().unwrap().remove(0);
+        destination.delete_blob(&PathBuf::from(&lost_blob)).unwrap();
+        let repaired = source.mirror(&mut destination, true).unwrap();
+        let unchanged = source.mirror(&mut destination, true).unwrap();
+
+        assert_eq!(first.snapshots_added, vec![snapshot_id.clone()]);
+        assert!(tagged.snapshots_added.is_empty());
+        assert_eq!(tagged.snapshots_updated, vec![snapshot_id.clone()]);
+        assert_eq!(destination.snapshots[0].tags, vec!["kept"]);
+        assert!(repaired.snapshots_updated.is_empty());
+        assert_eq!((repaired.blobs_repaired, repaired.blobs_verified), (1, 2));
+        assert!(repaired.verify_errors.is_empty());
+        assert!(unchanged.snapshots_updated.is_empty());
+        assert_eq!(unchanged.blobs_repaired, 0);
+    }
+}
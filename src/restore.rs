@@ -0,0 +1,601 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::backup_vault::*;
+use crate::filter::{FileFilter, RestoreFilter};
+
+/// What happens to files that already exist in the target.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Overwrite {
+    #[default]
+    Always,
+    Never,
+    /// Only if the file in the snapshot was modified later, or if its content differs when the snapshot lacks the time
+    IfNewer,
+    /// Only if size or content differ
+    IfChanged,
+}
+
+impl Overwrite {
+    pub fn parse(value: &str) -> Result<Overwrite, String> {
+        match value {
+            "always" => Ok(Overwrite::Always),
+            "never" => Ok(Overwrite::Never),
+            "if-newer" => Ok(Overwrite::IfNewer),
+            "if-changed" => Ok(Overwrite::IfChanged),
+            _ => Err(format!("invalid overwrite policy '{}', expected always, never, if-newer or if-changed", value)),
+        }
+    }
+}
+
+pub enum RestoreTarget {
+    /// Files are restored below the directory, relative to the parent of their backed up path
+    Directory(PathBuf),
+    /// Files are restored to the absolute paths they were backed up from
+    OriginalLocation,
+}
+
+#[derive(Default)]
+pub struct RestoreOptions {
+    pub filter: RestoreFilter,
+    pub overwrite: Overwrite,
+    /// Only print what would be done
+    pub dry_run: bool,
+    /// Remove files below the restored paths that are not in the snapshot
+    pub delete: bool,
+    /// Restore files with damaged or missing blobs with zeros in their place
+    pub partial: bool,
+    /// Hash every restored file and compare it with the backup
+    pub verify: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestoreAction {
+    Create,
+    Overwrite,
+    Skip,
+    Delete,
+    /// Restoring in place a file that was backed up without its absolute path
+    Fail,
+}
+
+/// A file the restore creates, overwrites, skips or deletes.
+pub struct PlannedFile<'a> {
+    pub path: PathBuf,
+    pub action: RestoreAction,
+    pub vault_file: Option<&'a VaultFile>,
+}
+
+/// A file that could not be restored, or with `partial` was restored with zero-filled gaps.
+#[derive(Debug, Serialize)]
+pub struct FailedFile {
+    pub path: PathBuf,
+    pub reason: String,
+    /// Offset and length of the zero-filled ranges of a partially restored file
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub zero_filled: Vec<(u64, u64)>,
+}
+
+/// The damaged blobs of a partially restored file and the ranges that were zero-filled for them.
+struct Damage {
+    reason: String,
+    zero_filled: Vec<(u64, u64)>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RestoreReport {
+    pub restored: usize,
+    pub overwritten: usize,
+    pub skipped: usize,
+    pub deleted: usize,
+    pub partially_restored: usize,
+    /// Restored files whose content was checked against the backup with `verify`
+    pub verified: usize,
+    pub failed: Vec<FailedFile>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    pub verified: usize,
+    pub failed: Vec<FailedFile>,
+}
+
+impl BackupVault {
+    /// Restores the files of a snapshot the filter selects. Existing files are handled as the
+    /// overwrite policy says, restored files get their modification time and permissions back.
+    /// Files that fail are reported and the restore goes on with the next one.
+    pub fn restore(&self, snapshot_id: &String, target: &RestoreTarget, options: &RestoreOptions) -> Result<RestoreReport, BackupError> {
+        let snapshot = match self.snapshots.iter().find(|s| s.snapshot_id == *snapshot_id) {
+            Some(snapshot) => snapshot,
+            None => {
+                println!("Snapshot not found");
+                return Err(BackupError::VaultReadError);
+            }
+        };
+
+        let plan = plan_restore(snapshot, target, options);
+        let mut report = RestoreReport::default();
+
+        if plan.is_empty() {
+            println!("No files of the snapshot match the given paths and patterns");
+            return Ok(report);
+        }
+
+        if options.dry_run {
+            for planned in &plan {
+                let action = match planned.action {
+                    RestoreAction::Create => "restore",
+                    RestoreAction::Overwrite => "overwrite",
+                    RestoreAction::Skip => "skip",
+                    RestoreAction::Delete => "delete",
+                    RestoreAction::Fail => "can't restore",
+                };
+
+                println!("{} {}", action, planned.path.display());
+            }
+
+            let count = |action: RestoreAction| plan.iter().filter(|planned| planned.action == action).count();
+
+            println!(
+                "Would restore {} files, overwrite {}, skip {} and delete {}",
+                count(RestoreAction::Create), count(RestoreAction::Overwrite), count(RestoreAction::Skip), count(RestoreAction::Delete)
+            );
+
+            if count(RestoreAction::Fail) > 0 {
+                println!("{} files were backed up without their absolute path and can't be restored in place", count(RestoreAction::Fail));
+            }
+
+            return Ok(report);
+        }
+
+        println!("Restoring backup...");
+
+        for planned in &plan {
+            match (planned.action, planned.vault_file) {
+                (RestoreAction::Create | RestoreAction::Overwrite, Some(vault_file)) => match self.restore_file(vault_file, &planned.path, options.partial) {
+                    Ok(None) => {
+                        match planned.action {
+                            RestoreAction::Create => report.restored += 1,
+                            _ => report.overwritten += 1,
+                        }
+
+                        if options.verify {
+                            match verify_file(vault_file, &planned.path) {
+                                Ok(_) => report.verified += 1,
+                                Err(reason) => {
+                                    println!("Verifying {} failed: {}", planned.path.display(), reason);
+                                    report.failed.push(FailedFile { path: planned.path.clone(), reason, zero_filled: vec![] });
+                                }
+                            }
+                        }
+                    },
+                    Ok(Some(damaged)) => {
+                        println!("Restored {} partially: {}", planned.path.display(), damaged.reason);
+                        report.partially_restored += 1;
+                        report.failed.push(damaged);
+                    },
+                    Err(failed) => {
+                        println!("Failed to restore {}: {}", planned.path.display(), failed.reason);
+                        report.failed.push(failed);
+                    }
+                },
+                (RestoreAction::Delete, _) => match fs::remove_file(&planned.path) {
+                    Ok(_) => report.deleted += 1,
+                    Err(err) => {
+                        println!("Failed to delete {}: {}", planned.path.display(), err);
+                        report.failed.push(FailedFile {
+                            path: planned.path.clone(),
+                            reason: format!("failed to delete: {}", err),
+                            zero_filled: vec![],
+                        });
+                    }
+                },
+                (RestoreAction::Fail, _) => {
+                    println!("Failed to restore {}: {}", planned.path.display(), NOT_ABSOLUTE);
+                    report.failed.push(FailedFile { path: planned.path.clone(), reason: NOT_ABSOLUTE.to_string(), zero_filled: vec![] });
+                },
+                _ => report.skipped += 1,
+            }
+        }
+
+        println!(
+            "Restored {} files, overwrote {}, skipped {} and deleted {}",
+            report.restored, report.overwritten, report.skipped, report.deleted
+        );
+
+        if options.verify {
+            println!("Verified {} files", report.verified);
+        }
+
+        if !report.failed.is_empty() {
+            println!("{} files failed, {} of them were restored partially", report.failed.len(), report.partially_restored);
+        }
+
+        Ok(report)
+    }
+
+    /// Writes the file next to its final path and moves it into place once complete, so a failed
+    /// restore leaves an existing file untouched. Returns the damage of a partially restored file.
+    fn restore_file(&self, vault_file: &VaultFile, path: &Path, partial: bool) -> Result<Option<FailedFile>, FailedFile> {
+        let failed = |reason: String| FailedFile { path: path.to_path_buf(), reason, zero_filled: vec![] };
+
+        if let Some(parent) = path.parent() {
+            if let Err(err) = fs::create_dir_all(parent) {
+                return Err(failed(format!("failed to create directory {}: {}", parent.display(), err)));
+            }
+        }
+
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp_path = path.with_file_name(format!(".{}.quicky-restore", file_name));
+
+        let result = self.write_restored_file(vault_file, &temp_path, partial)
+            .map_err(failed)
+            .and_then(|damage| match fs::rename(&temp_path, path) {
+                Ok(_) => Ok(damage.map(|damage| FailedFile { path: path.to_path_buf(), reason: damage.reason, zero_filled: damage.zero_filled })),
+                Err(err) => Err(failed(format!("failed to move the restored file into place: {}", err))),
+            });
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+
+        result
+    }
+
+    /// With `partial`, damaged blobs are replaced by zeros, which keeps the size and the intact
+    /// blobs of the file. Their size is only exact if one blob of the file is damaged, the missing
+    /// bytes are split evenly between several damaged blobs.
+    fn write_restored_file(&self, vault_file: &VaultFile, path: &Path, partial: bool) -> Result<Option<Damage>, String> {
+        let mut file = File::create(path).map_err(|err| format!("failed to create file: {}", err))?;
+        let write_error = |err: std::io::Error| format!("failed to write file: {}", err);
+        let mut damage = None;
+
+        if partial {
+            let blobs: Vec<Result<Vec<u8>, String>> = vault_file.vault_paths.iter()
+                .map(|vault_path| self.read_blob(vault_path).map_err(|err| blob_error(vault_path, err)))
+                .collect();
+
+            let reasons: Vec<String> = blobs.iter().filter_map(|blob| blob.as_ref().err().cloned()).collect();
+
+            let readable: u64 = blobs.iter().filter_map(|blob| blob.as_ref().ok()).map(|blob| blob.len() as u64).sum();
+            let mut missing = vault_file.file_size.saturating_sub(readable);
+            let mut damaged = reasons.len() as u64;
+            let mut zero_filled = vec![];
+            let mut offset = 0;
+
+            for blob in blobs {
+                let length = match blob {
+                    Ok(buffer) => {
+                        file.write_all(&buffer).map_err(write_error)?;
+                        buffer.len() as u64
+                    },
+                    Err(_) => {
+                        let length = missing / damaged;
+                        missing -= length;
+                        damaged -= 1;
+
+                        std::io::copy(&mut std::io::repeat(0).take(length), &mut file).map_err(write_error)?;
+                        zero_filled.push((offset, length));
+                        length
+                    }
+                };
+
+                offset += length;
+            }
+
+            if !reasons.is_empty() {
+                damage = Some(Damage { reason: reasons.join(", "), zero_filled });
+            }
+        } else {
+            for vault_path in &vault_file.vault_paths {
+                let buffer = self.read_blob(vault_path).map_err(|err| blob_error(vault_path, err))?;
+                file.write_all(&buffer).map_err(write_error)?;
+            }
+        }
+
+        if let Some(modified) = vault_file.file_modified {
+            let _ = file.set_modified(UNIX_EPOCH + Duration::from_secs(modified));
+        }
+
+        set_mode(path, vault_file.file_mode);
+
+        Ok(damage)
+    }
+}
+
+fn blob_error(vault_path: &Path, err: BackupError) -> String {
+    match err {
+        BackupError::VaultDoesNotExist => format!("blob {} is missing", vault_path.display()),
+        BackupError::VaultDecryptionError => format!("blob {} is damaged", vault_path.display()),
+        err => format!("failed to read blob {}: {}", vault_path.display(), err),
+    }
+}
+
+/// Decides what happens to every selected file of the snapshot and, with `delete`, to the files
+/// below the restored backup paths that the snapshot doesn't have.
+pub fn plan_restore<'a>(snapshot: &'a Snapshot, target: &RestoreTarget, options: &RestoreOptions) -> Vec<PlannedFile<'a>> {
+    let mut plan = vec![];
+
+    for vault_file in &snapshot.snapshot_files {
+        let restore_path = snapshot.restore_path(&vault_file.file_path);
+
+        if !options.filter.matches(&vault_file.file_path, &restore_path) {
+            continue;
+        }
+
+        let Some(path) = target_path(vault_file, restore_path, target) else {
+            plan.push(PlannedFile { path: vault_file.file_path.clone(), action: RestoreAction::Fail, vault_file: Some(vault_file) });
+            continue;
+        };
+
+        let action = match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => {
+                println!("{} is a directory, skipping it", path.display());
+                RestoreAction::Skip
+            },
+            Ok(metadata) if should_overwrite(options.overwrite, vault_file, &path, &metadata) => RestoreAction::Overwrite,
+            Ok(_) => RestoreAction::Skip,
+            Err(_) => RestoreAction::Create,
+        };
+
+        plan.push(PlannedFile { path, action, vault_file: Some(vault_file) });
+    }
+
+    if options.delete {
+        let restored: HashSet<PathBuf> = plan.iter().map(|planned| planned.path.clone()).collect();
+
+        for root in &snapshot.paths {
+            let (root_path, filter_root) = match target {
+                RestoreTarget::Directory(dir) => (dir.join(snapshot.restore_path(root)), dir.clone()),
+                RestoreTarget::OriginalLocation => (root.clone(), PathBuf::new()),
+            };
+
+            if !root_path.exists() {
+                continue;
+            }
+
+            for path in FileFilter::default().walk(&root_path) {
+                // Like restored files, files in the target are only deleted if the filter selects them
+                let relative_path = path.strip_prefix(&filter_root).unwrap_or(&path);
+
+                if !restored.contains(&path) && options.filter.matches(relative_path, relative_path) {
+                    plan.push(PlannedFile { path, action: RestoreAction::Delete, vault_file: None });
+                }
+            }
+        }
+    }
+
+    plan
+}
+
+const NOT_ABSOLUTE: &str = "backed up without its absolute path, can't be restored in place";
+
+/// The path the file is restored to, `None` if it is restored in place but was backed up without
+/// its absolute path, like streams from stdin and imported archives.
+fn target_path(vault_file: &VaultFile, restore_path: PathBuf, target: &RestoreTarget) -> Option<PathBuf> {
+    match target {
+        RestoreTarget::Directory(dir) => Some(dir.join(restore_path)),
+        RestoreTarget::OriginalLocation if vault_file.file_path.is_absolute() => Some(vault_file.file_path.clone()),
+        RestoreTarget::OriginalLocation => None,
+    }
+}
+
+/// Checks the restored files the filter selects against the sizes and hashes in the snapshot.
+pub fn verify_restore(snapshot: &Snapshot, target: &RestoreTarget, filter: &RestoreFilter) -> VerifyReport {
+    let mut report = VerifyReport::default();
+
+    for vault_file in &snapshot.snapshot_files {
+        let restore_path = snapshot.restore_path(&vault_file.file_path);
+
+        if !filter.matches(&vault_file.file_path, &restore_path) {
+            continue;
+        }
+
+        let Some(path) = target_path(vault_file, restore_path, target) else {
+            println!("{}: {}", vault_file.file_path.display(), NOT_ABSOLUTE);
+            report.failed.push(FailedFile { path: vault_file.file_path.clone(), reason: NOT_ABSOLUTE.to_string(), zero_filled: vec![] });
+            continue;
+        };
+
+        match verify_file(vault_file, &path) {
+            Ok(_) => report.verified += 1,
+            Err(reason) => {
+                println!("{}: {}", path.display(), reason);
+                report.failed.push(FailedFile { path, reason, zero_filled: vec![] });
+            }
+        }
+    }
+
+    report
+}
+
+fn verify_file(vault_file: &VaultFile, path: &Path) -> Result<(), String> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Err("not a regular file".to_string()),
+        Err(_) => return Err("missing".to_string()),
+    };
+
+    if metadata.len() != vault_file.file_size {
+        return Err(format!("size is {} bytes instead of {}", metadata.len(), vault_file.file_size));
+    }
+
+    match hash_file(path) {
+        Ok(hash) if hash == vault_file.file_hash => Ok(()),
+        Ok(_) => Err("content does not match the backup".to_string()),
+        Err(err) => Err(format!("failed to read: {}", err)),
+    }
+}
+
+fn should_overwrite(overwrite: Overwrite, vault_file: &VaultFile, path: &Path, metadata: &fs::Metadata) -> bool {
+    match overwrite {
+        Overwrite::Always => true,
+        Overwrite::Never => false,
+        Overwrite::IfNewer => match (vault_file.file_modified, file_modified(metadata)) {
+            (Some(snapshot_modified), Some(modified)) => snapshot_modified > modified,
+            _ => is_changed(vault_file, path, metadata),
+        },
+        Overwrite::IfChanged => is_changed(vault_file, path, metadata),
+    }
+}
+
+fn is_changed(vault_file: &VaultFile, path: &Path, metadata: &fs::Metadata) -> bool {
+    metadata.len() != vault_file.file_size || hash_file(path).map_or(true, |hash| hash != vault_file.file_hash)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: Option<u32>) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(mode) = mode {
+        let _ = fs::set_permissions(path, fs::Permissions::from_mode(mode));
+    }
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: Option<u32>) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_vault::tests::{temp_vault, TempDir};
+
+    fn vault_file(file_path: &Path, content: &[u8], modified: Option<u64>) -> VaultFile {
+        VaultFile {
+            file_name: file_path.file_name().unwrap().to_str().unwrap().to_string(),
+            file_hash: blake3::hash(content).to_hex().to_string(),
+            file_path: file_path.to_path_buf(),
+            file_size: content.len() as u64,
+            vault_paths: vec![],
+            file_modified: modified,
+            file_mode: None,
+        }
+    }
+
+    #[test]
+    fn plan() {
+        let root = TempDir::new("restore");
+        let target = root.join("target");
+        fs::create_dir_all(target.join("data")).unwrap();
+
+        fs::write(target.join("data/same"), "same").unwrap();
+        fs::write(target.join("data/changed"), "local").unwrap();
+        fs::write(target.join("data/extra"), "extra").unwrap();
+        fs::write(target.join("unrelated"), "unrelated").unwrap();
+
+        let source = root.join("data");
+        let snapshot = Snapshot {
+            snapshot_id: "1".to_string(),
+            snapshot_time: "0".to_string(),
+            snapshot_files: vec![
+                vault_file(&source.join("same"), b"same", Some(0)),
+                vault_file(&source.join("changed"), b"snapshot", Some(0)),
+                vault_file(&source.join("new"), b"new", None),
+            ],
+            hostname: String::new(),
+            username: String::new(),
+            paths: vec![source.clone()],
+            program_version: String::new(),
+            description: None,
+            tags: vec![],
+        };
+
+        let actions = |overwrite: Overwrite, delete: bool| {
+            let options = RestoreOptions { overwrite, delete, ..Default::default() };
+            let plan = plan_restore(&snapshot, &RestoreTarget::Directory(target.clone()), &options);
+
+            plan.iter()
+                .map(|planned| (planned.path.strip_prefix(&target).unwrap().display().to_string(), planned.action))
+                .collect::<Vec<_>>()
+        };
+
+        let always = actions(Overwrite::Always, true);
+        let never = actions(Overwrite::Never, false);
+        let if_changed = actions(Overwrite::IfChanged, false);
+        let if_newer = actions(Overwrite::IfNewer, false);
+
+        assert_eq!(always, vec![
+            ("data/same".to_string(), RestoreAction::Overwrite),
+            ("data/changed".to_string(), RestoreAction::Overwrite),
+            ("data/new".to_string(), RestoreAction::Create),
+            ("data/extra".to_string(), RestoreAction::Delete),
+        ]);
+        assert_eq!(never[..2], [("data/same".to_string(), RestoreAction::Skip), ("data/changed".to_string(), RestoreAction::Skip)]);
+        assert_eq!(if_changed[..2], [("data/same".to_string(), RestoreAction::Skip), ("data/changed".to_string(), RestoreAction::Overwrite)]);
+        // The local files were modified after the snapshot's files
+        assert_eq!(if_newer[..2], [("data/same".to_string(), RestoreAction::Skip), ("data/changed".to_string(), RestoreAction::Skip)]);
+        assert!(Overwrite::parse("sometimes").is_err());
+    }
+
+    #[test]
+    fn relative_path_in_place() {
+        let missing = std::env::temp_dir().join(format!("quicky_restore_{}", uuid::Uuid::new_v4()));
+        let snapshot = Snapshot {
+            snapshot_id: "1".to_string(),
+            snapshot_time: "0".to_string(),
+            snapshot_files: vec![vault_file(Path::new("stdin"), b"stream", None), vault_file(&missing, b"file", None)],
+            hostname: String::new(),
+            username: String::new(),
+            paths: vec![PathBuf::from("stdin"), missing.clone()],
+            program_version: String::new(),
+            description: None,
+            tags: vec![],
+        };
+
+        let plan = plan_restore(&snapshot, &RestoreTarget::OriginalLocation, &RestoreOptions::default());
+        let verify_report = verify_restore(&snapshot, &RestoreTarget::OriginalLocation, &RestoreFilter::default());
+
+        let actions = plan.iter().map(|planned| (planned.path.clone(), planned.action)).collect::<Vec<_>>();
+        assert_eq!(actions, vec![(PathBuf::from("stdin"), RestoreAction::Fail), (missing, RestoreAction::Create)]);
+        assert_eq!(verify_report.failed.len(), 2);
+        assert_eq!(verify_report.failed[0].reason, NOT_ABSOLUTE);
+        assert_eq!(verify_report.failed[1].reason, "missing");
+    }
+
+    #[test]
+    fn damaged_blob() {
+        let (root, mut vault) = temp_vault("restore");
+        let source = root.join("data");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("intact"), "intact").unwrap();
+        fs::write(source.join("damaged"), "damaged").unwrap();
+
+        vault.backup(&vec![source.clone()], &BackupOptions::default()).unwrap();
+
+        let snapshot = &vault.snapshots[0];
+        let damaged = snapshot.snapshot_files.iter().find(|f| f.file_name == "damaged").unwrap();
+        let blob_path = root.join("vault").join(blob_name(&damaged.vault_paths[0]));
+        let mut blob = fs::read(&blob_path).unwrap();
+        *blob.last_mut().unwrap() ^= 1;
+        fs::write(&blob_path, blob).unwrap();
+
+        let restore = |dir: &str, partial: bool| {
+            let options = RestoreOptions { partial, ..Default::default() };
+            vault.restore(&snapshot.snapshot_id, &RestoreTarget::Directory(root.join(dir)), &options).unwrap()
+        };
+
+        let report = restore("failed", false);
+        let partial_report = restore("partial", true);
+
+        let target = RestoreTarget::Directory(root.join("partial"));
+        let verify_report = verify_restore(snapshot, &target, &RestoreFilter::default());
+
+        let intact = fs::read_to_string(root.join("failed/data/intact")).unwrap();
+        let failed_exists = root.join("failed/data/damaged").exists();
+        let zero_filled = fs::read(root.join("partial/data/damaged")).unwrap();
+
+        assert_eq!(intact, "intact");
+        assert_eq!((report.restored, report.failed.len(), report.partially_restored), (1, 1, 0));
+        assert!(!failed_exists);
+        assert_eq!((partial_report.restored, partial_report.failed.len(), partial_report.partially_restored), (1, 1, 1));
+        assert_eq!(partial_report.failed[0].zero_filled, vec![(0, 7)]);
+        assert_eq!(zero_filled, vec![0; 7]);
+        assert_eq!(verify_report.verified, 1);
+        assert_eq!(verify_report.failed[0].reason, "content does not match the backup");
+    }
+}
@@ -0,0 +1,149 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::backup_vault::*;
+use crate::snapshot::{snapshot_time, SnapshotFilter};
+
+/// Which snapshots to keep, like restic's forget: the newest `keep_last` and the newest snapshot of
+/// each of the last `keep_daily` days, `keep_weekly` weeks and so on. A snapshot kept by any rule
+/// stays. Days, weeks, months and years are counted in UTC.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_hourly: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+    pub keep_yearly: usize,
+}
+
+impl RetentionPolicy {
+    /// A policy without any rule keeps everything instead of nothing.
+    pub fn is_empty(&self) -> bool {
+        self.rules().iter().all(|(keep, _)| *keep == 0)
+    }
+
+    fn rules(&self) -> [(usize, Option<&'static str>); 6] {
+        [
+            (self.keep_last, None),
+            (self.keep_hourly, Some("%Y-%m-%d %H")),
+            (self.keep_daily, Some("%Y-%m-%d")),
+            (self.keep_weekly, Some("%G-%V")),
+            (self.keep_monthly, Some("%Y-%m")),
+            (self.keep_yearly, Some("%Y")),
+        ]
+    }
+
+    /// The snapshots the policy doesn't keep, newest first.
+    pub fn expired<'a>(&self, snapshots: &[&'a Snapshot]) -> Vec<&'a Snapshot> {
+        if self.is_empty() {
+            return vec![];
+        }
+
+        // Snapshots are appended to the vault, so within the same second the later one is newer
+        let mut snapshots: Vec<&Snapshot> = snapshots.iter().rev().copied().collect();
+        snapshots.sort_by_key(|snapshot| std::cmp::Reverse(snapshot_time(snapshot)));
+
+        let mut kept = vec![false; snapshots.len()];
+
+        for (keep, format) in self.rules() {
+            let mut last_period = None;
+            let mut remaining = keep;
+
+            for (index, snapshot) in snapshots.iter().enumerate() {
+                if remaining == 0 {
+                    break;
+                }
+
+                let time = DateTime::<Utc>::from_timestamp(snapshot_time(snapshot) as i64, 0).unwrap_or_default();
+                // keep_last treats every snapshot as its own period
+                let period = format.map(|format| time.format(format).to_string()).unwrap_or(index.to_string());
+
+                if last_period.as_ref() != Some(&period) {
+                    kept[index] = true;
+                    remaining -= 1;
+                    last_period = Some(period);
+                }
+            }
+        }
+
+        snapshots.into_iter().zip(kept).filter(|(_, kept)| !kept).map(|(snapshot, _)| snapshot).collect()
+    }
+}
+
+impl BackupVault {
+    /// Deletes the snapshots matching `filter` that the policy doesn't keep, and returns their ids.
+    /// Like deleting a single snapshot, their blobs stay in the vault.
+    pub fn apply_retention(&mut self, policy: &RetentionPolicy, filter: &SnapshotFilter) -> Result<Vec<String>, BackupError> {
+        self.lock()?;
+        let result = self.apply_retention_locked(policy, filter);
+        self.unlock();
+
+        result
+    }
+
+    fn apply_retention_locked(&mut self, policy: &RetentionPolicy, filter: &SnapshotFilter) -> Result<Vec<String>, BackupError> {
+        let candidates: Vec<&Snapshot> = self.snapshots.iter().filter(|snapshot| filter.matches(snapshot)).collect();
+        let expired: Vec<String> = policy.expired(&candidates).iter().map(|snapshot| snapshot.snapshot_id.clone()).collect();
+
+        if expired.is_empty() {
+            return Ok(expired);
+        }
+
+        let snapshots = self.snapshots.clone();
+        self.snapshots.retain(|snapshot| !expired.contains(&snapshot.snapshot_id));
+        let result = self.save_index();
+
+        if result.is_err() {
+            self.snapshots = snapshots;
+        }
+
+        result.map(|_| expired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(snapshot_id: &str, time: &str) -> Snapshot {
+        let time = DateTime::parse_from_rfc3339(time).unwrap().timestamp();
+
+        Snapshot {
+            snapshot_id: snapshot_id.to_string(),
+            snapshot_time: time.to_string(),
+            snapshot_files: vec![],
+            hostname: String::new(),
+            username: String::new(),
+            paths: vec![],
+            program_version: String::new(),
+            description: None,
+            tags: vec![],
+        }
+    }
+
+    fn expired(policy: &RetentionPolicy, snapshots: &[Snapshot]) -> Vec<String> {
+        let snapshots: Vec<&Snapshot> = snapshots.iter().collect();
+        policy.expired(&snapshots).iter().map(|snapshot| snapshot.snapshot_id.clone()).collect()
+    }
+
+    #[test]
+    fn policy() {
+        let snapshots = vec![
+            snapshot("jan", "2026-01-15T12:00:00Z"),
+            snapshot("feb", "2026-02-15T12:00:00Z"),
+            snapshot("mon-morning", "2026-03-02T08:00:00Z"),
+            snapshot("mon-evening", "2026-03-02T20:00:00Z"),
+            snapshot("tue", "2026-03-03T20:00:00Z"),
+            snapshot("wed", "2026-03-04T20:00:00Z"),
+        ];
+
+        let daily = RetentionPolicy { keep_daily: 2, ..Default::default() };
+        let last_and_monthly = RetentionPolicy { keep_last: 1, keep_monthly: 3, ..Default::default() };
+
+        assert_eq!(expired(&daily, &snapshots), vec!["mon-evening", "mon-morning", "feb", "jan"]);
+        assert_eq!(expired(&last_and_monthly, &snapshots), vec!["tue", "mon-evening", "mon-morning"]);
+        assert!(expired(&RetentionPolicy::default(), &snapshots).is_empty());
+    }
+}
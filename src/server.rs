@@ -0,0 +1,489 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use tiny_http::{Method, Request, Response, Server, SslConfig};
+
+use crate::backend::*;
+
+const WORKER_THREADS: usize = 8;
+
+/// Config, index and lock bodies are read into memory and refused above this size. Blobs can be as
+/// large as the files they were backed up from, so they are streamed to disk instead.
+const MAX_BODY_SIZE: usize = 256 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum ServerError {
+    ConfigError,
+    BindError,
+}
+
+pub struct ServerOptions {
+    pub listen: String,
+    pub root: PathBuf,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub htpasswd: Option<PathBuf>,
+    pub admin_htpasswd: Option<PathBuf>,
+    pub append_only: bool,
+}
+
+struct ServerState {
+    root: PathBuf,
+    users: Option<HashMap<String, String>>,
+    admins: Option<HashMap<String, String>>,
+    verified: Mutex<HashMap<String, Role>>,
+    append_only: bool,
+}
+
+/// Admins are not bound by append-only mode, so they can delete snapshots and data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Client,
+    Admin,
+}
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+/// A server that is listening but not yet answering requests, see `bind` and `run`.
+pub struct VaultServer {
+    server: Arc<Server>,
+    state: Arc<ServerState>,
+}
+
+/// Serves every vault under `options.root` over HTTP, vault `name` being reachable as `rest:http://host:port/name`.
+///
+/// Routes:
+/// - `POST /<vault>/` creates the vault
+/// - `GET|HEAD|PUT|DELETE /<vault>/config` and `/<vault>/index`
+/// - `GET /<vault>/blobs/` and `/<vault>/locks/` list the objects as a json array
+/// - `GET|HEAD|PUT|DELETE /<vault>/blobs/<name>` and `/<vault>/locks/<name>`
+///
+/// A `PUT` with `If-None-Match: *` only creates the object and answers 409 if it exists, which is
+/// how clients take the vault lock.
+///
+/// In append-only mode (server wide or per vault) only locks can be deleted, existing blobs and config
+/// can't be overwritten and index rewrites may only add snapshots. Admins are exempt.
+pub fn serve(options: ServerOptions) -> Result<(), ServerError> {
+    bind(options)?.run();
+
+    Ok(())
+}
+
+/// Reads the server configuration and starts listening, `run` then answers the requests.
+pub fn bind(options: ServerOptions) -> Result<VaultServer, ServerError> {
+    if fs::create_dir_all(&options.root).is_err() {
+        println!("Failed to create server root directory");
+        return Err(ServerError::ConfigError);
+    }
+
+    let users = match &options.htpasswd {
+        Some(path) => Some(read_htpasswd(path)?),
+        None => {
+            println!("Warning: no htpasswd file given, the server accepts unauthenticated requests");
+            None
+        }
+    };
+
+    let admins = match &options.admin_htpasswd {
+        Some(path) => Some(read_htpasswd(path)?),
+        None => None,
+    };
+
+    let server = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => {
+            let (certificate, private_key) = match (fs::read(cert), fs::read(key)) {
+                (Ok(certificate), Ok(private_key)) => (certificate, private_key),
+                _ => {
+                    println!("Failed to read TLS certificate or key");
+                    return Err(ServerError::ConfigError);
+                }
+            };
+
+            Server::https(&options.listen, SslConfig { certificate, private_key })
+        },
+        (None, None) => Server::http(&options.listen),
+        _ => {
+            println!("Both --tls-cert and --tls-key are required for TLS");
+            return Err(ServerError::ConfigError);
+        }
+    };
+
+    let server = match server {
+        Ok(server) => Arc::new(server),
+        Err(err) => {
+            println!("Failed to listen on {}, {}", options.listen, err);
+            return Err(ServerError::BindError);
+        }
+    };
+
+    let state = Arc::new(ServerState {
+        root: options.root,
+        users,
+        admins,
+        verified: Mutex::new(HashMap::new()),
+        append_only: options.append_only,
+    });
+
+    let server = VaultServer { server, state };
+    let listen = server.local_addr().map(|addr| addr.to_string()).unwrap_or(options.listen);
+    println!("Serving vaults from {} on {}", server.state.root.display(), listen);
+
+    if server.state.append_only {
+        println!("Append-only mode, only admins can delete or rewrite vault data");
+    }
+
+    Ok(server)
+}
+
+impl VaultServer {
+    /// The address the server listens on, which tells the port when listening on port 0.
+    pub fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Answers requests on `WORKER_THREADS` threads, blocking for as long as the server runs.
+    pub fn run(self) {
+        let workers: Vec<_> = (0..WORKER_THREADS).map(|_| {
+            let server = self.server.clone();
+            let state = self.state.clone();
+
+            thread::spawn(move || {
+                while let Ok(mut request) = server.recv() {
+                    let response = handle_request(&state, &mut request);
+                    println!("{} {} -> {}", request.method(), request.url(), response.status_code().0);
+                    let _ = request.respond(response);
+                }
+            })
+        }).collect();
+
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn read_htpasswd(path: &Path) -> Result<HashMap<String, String>, ServerError> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(_) => {
+            println!("Failed to read htpasswd file {}", path.display());
+            return Err(ServerError::ConfigError);
+        }
+    };
+
+    let mut users = HashMap::new();
+
+    for line in content.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        match line.split_once(':') {
+            Some((user, hash)) if hash.starts_with("$2") => {
+                users.insert(user.to_string(), hash.to_string());
+            },
+            _ => {
+                println!("Unsupported htpasswd entry, only bcrypt hashes are accepted: {}", line);
+                return Err(ServerError::ConfigError);
+            }
+        }
+    }
+
+    Ok(users)
+}
+
+fn authenticate(state: &ServerState, request: &Request) -> Option<Role> {
+    let header = request.headers().iter().find(|header| header.field.equiv("Authorization"));
+    let credentials = header.and_then(|header| header.value.as_str().strip_prefix("Basic ")).map(str::trim);
+
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None if state.users.is_none() => return Some(Role::Client),
+        None => return None,
+    };
+
+    // bcrypt is slow on purpose, so credentials that were verified once are remembered by their hash
+    let credentials_hash = blake3::hash(credentials.as_bytes()).to_hex().to_string();
+
+    if let Some(role) = state.verified.lock().unwrap().get(&credentials_hash) {
+        return Some(*role);
+    }
+
+    let decoded = BASE64.decode(credentials).ok().and_then(|decoded| String::from_utf8(decoded).ok())?;
+    let (user, password) = decoded.split_once(':')?;
+
+    let is_valid = |users: &Option<HashMap<String, String>>| match users.as_ref().and_then(|users| users.get(user)) {
+        Some(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+        None => false,
+    };
+
+    let role = if is_valid(&state.admins) {
+        Role::Admin
+    } else if state.users.is_none() || is_valid(&state.users) {
+        Role::Client
+    } else {
+        return None;
+    };
+
+    state.verified.lock().unwrap().insert(credentials_hash, role);
+
+    Some(role)
+}
+
+/// A vault is append-only if the server runs in append-only mode or its config enables it.
+fn is_vault_append_only(state: &ServerState, backend: &LocalBackend) -> bool {
+    if state.append_only {
+        return true;
+    }
+
+    let config = backend.read(ObjectKind::Config, "").ok()
+        .and_then(|data| serde_json::from_slice::<serde_json::Value>(&data).ok());
+
+    match config {
+        Some(config) => config["append_only"].as_bool().unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Rewriting the index may only add snapshots: every stored snapshot must still be there unchanged,
+/// and every other field, like the salt and nonce, may not change. Dropping the key that older
+/// versions stored next to the salt and nonce is fine.
+fn is_index_append(old_index: &[u8], new_index: &[u8]) -> bool {
+    let (old_index, new_index) = match (
+        serde_json::from_slice::<serde_json::Value>(old_index),
+        serde_json::from_slice::<serde_json::Value>(new_index),
+    ) {
+        (Ok(old_index), Ok(new_index)) => (old_index, new_index),
+        _ => return false,
+    };
+
+    let (Some(mut old_fields), Some(mut new_fields)) = (old_index.as_object().cloned(), new_index.as_object().cloned()) else {
+        return false;
+    };
+
+    old_fields.remove("snapshots");
+    new_fields.remove("snapshots");
+
+    let old_key = old_fields.get_mut("crypto").and_then(|crypto| crypto.as_object_mut()).and_then(|crypto| crypto.remove("key"));
+    let new_key = new_fields.get_mut("crypto").and_then(|crypto| crypto.as_object_mut()).and_then(|crypto| crypto.remove("key"));
+
+    if old_fields != new_fields || (new_key.is_some() && new_key != old_key) {
+        return false;
+    }
+
+    let new_snapshots = match new_index["snapshots"].as_array() {
+        Some(snapshots) => snapshots,
+        None => return false,
+    };
+
+    match old_index["snapshots"].as_array() {
+        Some(old_snapshots) => old_snapshots.iter().all(|snapshot| new_snapshots.contains(snapshot)),
+        None => true,
+    }
+}
+
+fn status_response(status: u16) -> HttpResponse {
+    Response::from_data(Vec::new()).with_status_code(status)
+}
+
+fn error_response(err: BackendError) -> HttpResponse {
+    match err {
+        BackendError::NotFound => status_response(404),
+        BackendError::AlreadyExists => status_response(409),
+        BackendError::InvalidName | BackendError::InvalidLocation => status_response(400),
+        BackendError::Unauthorized => status_response(401),
+        BackendError::Forbidden => status_response(403),
+        BackendError::ConnectionError | BackendError::IoError => status_response(500),
+    }
+}
+
+fn handle_request(state: &ServerState, request: &mut Request) -> HttpResponse {
+    let role = match authenticate(state, request) {
+        Some(role) => role,
+        None => {
+            return status_response(401)
+                .with_header("WWW-Authenticate: Basic realm=\"quicky_backup\"".parse::<tiny_http::Header>().unwrap());
+        }
+    };
+
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+    let vault_name = segments[0];
+
+    if !is_valid_name(vault_name) {
+        return status_response(400);
+    }
+
+    let backend = LocalBackend::new(state.root.join(vault_name));
+    let method = request.method().clone();
+
+    let (kind, name) = match segments[1..] {
+        [] | [""] if method == Method::Post => {
+            return match backend.create() {
+                Ok(_) => status_response(201),
+                Err(err) => error_response(err),
+            };
+        },
+        [kind] | [kind, ""] if ObjectKind::parse(kind).is_some_and(|kind| kind.is_single()) => {
+            (ObjectKind::parse(kind).unwrap(), "")
+        },
+        [kind, ""] if method == Method::Get => {
+            return match ObjectKind::parse(kind).map(|kind| backend.list(kind)) {
+                Some(Ok(names)) => Response::from_data(serde_json::to_vec(&names).unwrap()),
+                Some(Err(err)) => error_response(err),
+                None => status_response(404),
+            };
+        },
+        [kind, name] => match ObjectKind::parse(kind) {
+            Some(kind) => (kind, name),
+            None => return status_response(404),
+        },
+        _ => return status_response(404),
+    };
+
+    let append_only = role != Role::Admin && is_vault_append_only(state, &backend);
+
+    match method {
+        Method::Head => match backend.exists(kind, name) {
+            Ok(true) => status_response(200),
+            Ok(false) => status_response(404),
+            Err(err) => error_response(err),
+        },
+        Method::Get => match backend.read(kind, name) {
+            Ok(data) => Response::from_data(data),
+            Err(err) => error_response(err),
+        },
+        Method::Put => {
+            if append_only && matches!(kind, ObjectKind::Config | ObjectKind::Blob) && backend.exists(kind, name).unwrap_or(true) {
+                return status_response(403);
+            }
+
+            let create_new = request.headers().iter().any(|header| header.field.equiv("If-None-Match") && header.value.as_str().trim() == "*");
+
+            if kind == ObjectKind::Blob && !create_new {
+                return match backend.write_stream(kind, name, request.as_reader()) {
+                    Ok(_) => status_response(200),
+                    Err(err) => error_response(err),
+                };
+            }
+
+            if request.body_length().is_some_and(|length| length > MAX_BODY_SIZE) {
+                return status_response(413);
+            }
+
+            let mut data = Vec::new();
+
+            // Chunked bodies come without a length, so the limit is enforced while reading as well
+            if request.as_reader().take(MAX_BODY_SIZE as u64 + 1).read_to_end(&mut data).is_err() {
+                return status_response(400);
+            }
+
+            if data.len() > MAX_BODY_SIZE {
+                return status_response(413);
+            }
+
+            if append_only && kind == ObjectKind::Index {
+                match backend.read(kind, name) {
+                    Ok(old_index) if !is_index_append(&old_index, &data) => return status_response(403),
+                    Ok(_) | Err(BackendError::NotFound) => {},
+                    Err(err) => return error_response(err),
+                }
+            }
+
+            let result = match create_new {
+                true => backend.write_new(kind, name, &data),
+                false => backend.write(kind, name, &data),
+            };
+
+            match result {
+                Ok(_) => status_response(200),
+                Err(err) => error_response(err),
+            }
+        },
+        Method::Delete => {
+            // Locks are removed by every client after it finishes, so they stay deletable
+            if append_only && kind != ObjectKind::Lock {
+                return status_response(403);
+            }
+
+            match backend.delete(kind, name) {
+                Ok(_) => status_response(200),
+                Err(err) => error_response(err),
+            }
+        },
+        _ => status_response(405),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_vault::tests::{test_key, TempDir};
+    use crate::backup_vault::{BackupOptions, BackupVault};
+    use crate::kdf::KdfParams;
+    use crate::restore::{RestoreOptions, RestoreTarget};
+
+    #[test]
+    fn index_append() {
+        let old_index = br#"{"snapshots":[{"snapshot_id":"a"}],"files":[],"crypto":{"salt":[1]}}"#;
+        let added = br#"{"snapshots":[{"snapshot_id":"a"},{"snapshot_id":"b"}],"files":[],"crypto":{"salt":[1]}}"#;
+        let removed = br#"{"snapshots":[{"snapshot_id":"b"}],"files":[],"crypto":{"salt":[1]}}"#;
+        let modified = br#"{"snapshots":[{"snapshot_id":"a","snapshot_files":[]}],"files":[],"crypto":{"salt":[1]}}"#;
+        let rekeyed = br#"{"snapshots":[{"snapshot_id":"a"}],"files":[],"crypto":{"salt":[2]}}"#;
+        let legacy = br#"{"snapshots":[{"snapshot_id":"a"}],"files":[],"crypto":{"key":[3],"salt":[1]}}"#;
+        let files_changed = br#"{"snapshots":[{"snapshot_id":"a"},{"snapshot_id":"b"}],"files":[{}],"crypto":{"salt":[1]}}"#;
+        let field_added = br#"{"snapshots":[{"snapshot_id":"a"},{"snapshot_id":"b"}],"files":[],"crypto":{"salt":[1]},"config":{}}"#;
+        let key_added = br#"{"snapshots":[{"snapshot_id":"a"}],"files":[],"crypto":{"key":[4],"salt":[1]}}"#;
+
+        assert!(is_index_append(old_index, added));
+        assert!(!is_index_append(old_index, removed));
+        assert!(!is_index_append(old_index, modified));
+        assert!(!is_index_append(old_index, rekeyed));
+        assert!(is_index_append(legacy, added));
+        assert!(is_index_append(legacy, legacy));
+        assert!(!is_index_append(old_index, files_changed));
+        assert!(!is_index_append(old_index, field_added));
+        assert!(!is_index_append(old_index, key_added));
+        assert!(!is_index_append(old_index, b"[]"));
+        assert!(!is_index_append(old_index, b"garbage"));
+    }
+
+    #[test]
+    fn rest_backend() {
+        let root = TempDir::new("server");
+        let options = ServerOptions {
+            listen: "127.0.0.1:0".to_string(),
+            root: root.join("vaults"),
+            tls_cert: None,
+            tls_key: None,
+            htpasswd: None,
+            admin_htpasswd: None,
+            append_only: true,
+        };
+        let server = bind(options).unwrap();
+        let url = format!("http://{}/vault", server.local_addr().unwrap());
+        thread::spawn(move || server.run());
+
+        let data = root.join("data");
+        fs::create_dir_all(&data).unwrap();
+        fs::write(data.join("a.txt"), "a").unwrap();
+
+        let location = PathBuf::from(format!("{}{}", REST_PREFIX, url));
+        let mut vault = BackupVault::create(&location, &test_key(), &KdfParams::default()).unwrap();
+        let snapshot_id = vault.backup(&vec![data.clone()], &BackupOptions::default()).unwrap();
+
+        let mut vault = BackupVault::open(&location, &test_key()).unwrap();
+        vault.restore(&snapshot_id, &RestoreTarget::Directory(root.join("restored")), &RestoreOptions::default()).unwrap();
+        assert_eq!(fs::read(root.join("restored").join("data").join("a.txt")).unwrap(), b"a");
+
+        let backend = RestBackend::new(&url).unwrap();
+        let blob = backend.list(ObjectKind::Blob).unwrap().remove(0);
+        assert_eq!(backend.delete(ObjectKind::Blob, &blob), Err(BackendError::Forbidden));
+        assert!(vault.delete_snapshot(&snapshot_id).is_err());
+        assert!(backend.exists(ObjectKind::Blob, &blob).unwrap());
+        assert_eq!(BackupVault::open(&location, &test_key()).unwrap().snapshots.len(), 1);
+    }
+}
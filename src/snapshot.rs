@@ -0,0 +1,386 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+
+use crate::backup_vault::*;
+
+/// Narrows down snapshots by their metadata. Empty fields don't filter, a snapshot has to
+/// come from one of the hosts and carry all of the tags and paths.
+#[derive(Debug, Default, Clone)]
+pub struct SnapshotFilter {
+    pub hosts: Vec<String>,
+    pub tags: Vec<String>,
+    pub paths: Vec<PathBuf>,
+}
+
+impl SnapshotFilter {
+    pub fn matches(&self, snapshot: &Snapshot) -> bool {
+        if !self.hosts.is_empty() && !self.hosts.contains(&snapshot.hostname) {
+            return false;
+        }
+
+        if !self.tags.iter().all(|tag| snapshot.tags.contains(tag)) {
+            return false;
+        }
+
+        self.paths.iter().all(|path| {
+            let path = fs::canonicalize(path).unwrap_or(path.clone());
+            snapshot.paths.contains(&path)
+        })
+    }
+}
+
+impl Snapshot {
+    /// Where a file is restored below the target: relative to the parent of the backed up path it
+    /// was found in, so backing up `/home/me/docs` restores `docs/...`. Of nested backed up paths the
+    /// outermost one counts, so their files stay below it. Only normal components are kept, a restore
+    /// never writes outside its target.
+    pub fn restore_path(&self, file_path: &Path) -> PathBuf {
+        let root = self.paths.iter()
+            .filter(|root| file_path.starts_with(root))
+            .min_by_key(|root| root.components().count());
+
+        let relative_path = match root.and_then(|root| root.parent()) {
+            Some(parent) => file_path.strip_prefix(parent).unwrap_or(file_path),
+            None => file_path,
+        };
+
+        relative_path.components()
+            .filter_map(|component| match component {
+                Component::Normal(component) => Some(component),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum SelectorError {
+    NoSnapshots,
+    NotFound(String),
+    Ambiguous(String, Vec<String>),
+}
+
+/// Picks one snapshot: `latest`, a unique id prefix, or the latest snapshot matching
+/// `latest:host=foo,tag=nightly`. `tag:`, `host:`, `path:` and `before:` are shorthands for
+/// `latest:` with a single condition.
+#[derive(Debug, Clone)]
+pub struct SnapshotSelector {
+    /// The selector as given, for messages
+    pub input: String,
+    pub id_prefix: Option<String>,
+    pub filter: SnapshotFilter,
+    /// Only snapshots taken before this unix time
+    pub before: Option<u64>,
+}
+
+impl SnapshotSelector {
+    pub fn latest() -> SnapshotSelector {
+        SnapshotSelector {
+            input: "latest".to_string(),
+            id_prefix: None,
+            filter: SnapshotFilter::default(),
+            before: None,
+        }
+    }
+
+    pub fn parse(selector: &str) -> Result<SnapshotSelector, String> {
+        let selector = selector.trim();
+
+        if selector == "latest" {
+            return Ok(SnapshotSelector::latest());
+        }
+
+        let conditions = match selector.split_once(':') {
+            Some(("latest", conditions)) => conditions.to_string(),
+            Some((key @ ("tag" | "host" | "path" | "before"), value)) => format!("{}={}", key, value),
+            Some(_) => return Err(format!("invalid snapshot selector '{}'", selector)),
+            None if !selector.is_empty() && selector.chars().all(|c| c.is_ascii_hexdigit() || c == '-') => {
+                return Ok(SnapshotSelector {
+                    input: selector.to_string(),
+                    id_prefix: Some(selector.to_lowercase()),
+                    ..SnapshotSelector::latest()
+                });
+            },
+            None => return Err(format!("invalid snapshot selector '{}', expected an id, latest, tag:, host:, path: or before:", selector)),
+        };
+
+        let mut parsed = SnapshotSelector {
+            input: selector.to_string(),
+            ..SnapshotSelector::latest()
+        };
+
+        for condition in conditions.split(',') {
+            match condition.split_once('=') {
+                Some(("host", host)) if !host.is_empty() => parsed.filter.hosts.push(host.to_string()),
+                Some(("tag", tag)) if !tag.is_empty() => parsed.filter.tags.push(tag.to_string()),
+                Some(("path", path)) if !path.is_empty() => parsed.filter.paths.push(PathBuf::from(path)),
+                Some(("before", time)) => parsed.before = Some(parse_time(time)?),
+                _ => return Err(format!("invalid snapshot condition '{}', expected host=, tag=, path= or before=", condition)),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Finds the snapshot a selector refers to, an id prefix has to be unique.
+    pub fn find<'a>(&self, snapshots: &'a [Snapshot]) -> Result<&'a Snapshot, SelectorError> {
+        if snapshots.is_empty() {
+            return Err(SelectorError::NoSnapshots);
+        }
+
+        let candidates: Vec<&Snapshot> = snapshots.iter().filter(|snapshot| self.matches(snapshot)).collect();
+
+        if self.id_prefix.is_some() {
+            return match candidates.as_slice() {
+                [snapshot] => Ok(snapshot),
+                [] => Err(SelectorError::NotFound(self.input.clone())),
+                _ => Err(SelectorError::Ambiguous(self.input.clone(), candidates.iter().map(|s| s.snapshot_id.clone()).collect())),
+            };
+        }
+
+        // The last one wins between snapshots taken in the same second
+        match candidates.into_iter().max_by_key(|snapshot| snapshot_time(snapshot)) {
+            Some(snapshot) => Ok(snapshot),
+            None => Err(SelectorError::NotFound(self.input.clone())),
+        }
+    }
+
+    fn matches(&self, snapshot: &Snapshot) -> bool {
+        if let Some(id_prefix) = &self.id_prefix {
+            if !snapshot.snapshot_id.starts_with(id_prefix.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(before) = self.before {
+            if snapshot_time(snapshot) >= before {
+                return false;
+            }
+        }
+
+        self.filter.matches(snapshot)
+    }
+}
+
+/// Accepts `2026-01-01`, `2026-01-01 12:00:00`, `2026-01-01T12:00:00` or RFC 3339, all but the last in UTC.
+pub fn parse_time(time: &str) -> Result<u64, String> {
+    let parsed = DateTime::parse_from_rfc3339(time).map(|time| time.timestamp())
+        .or_else(|_| NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").map(|time| time.and_utc().timestamp()))
+        .or_else(|_| NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S").map(|time| time.and_utc().timestamp()))
+        .or_else(|_| NaiveDate::parse_from_str(time, "%Y-%m-%d").map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp()));
+
+    match parsed {
+        Ok(timestamp) => Ok(timestamp.max(0) as u64),
+        Err(_) => Err(format!("invalid time '{}', expected e.g. 2026-01-01 or 2026-01-01 12:00:00", time)),
+    }
+}
+
+pub fn snapshot_time(snapshot: &Snapshot) -> u64 {
+    snapshot.snapshot_time.parse::<u64>().unwrap_or(0)
+}
+
+/// How the tags of a snapshot are changed, `set` replaces them before adding and removing.
+#[derive(Debug, Default)]
+pub struct TagChanges {
+    pub set: Option<Vec<String>>,
+    pub add: Vec<String>,
+    pub remove: Vec<String>,
+}
+
+impl TagChanges {
+    pub fn is_empty(&self) -> bool {
+        self.set.is_none() && self.add.is_empty() && self.remove.is_empty()
+    }
+
+    fn apply(&self, tags: &[String]) -> Vec<String> {
+        let mut tags = match &self.set {
+            Some(set) => set.clone(),
+            None => tags.to_vec(),
+        };
+
+        tags.extend(self.add.iter().cloned());
+
+        let remove = normalize_tags(&self.remove);
+        normalize_tags(&tags).into_iter().filter(|tag| !remove.contains(tag)).collect()
+    }
+}
+
+/// Trims tags and drops empty and repeated ones, keeping their order.
+pub fn normalize_tags(tags: &[String]) -> Vec<String> {
+    let mut normalized: Vec<String> = vec![];
+
+    for tag in tags.iter().map(|tag| tag.trim()) {
+        if !tag.is_empty() && !normalized.iter().any(|t| t == tag) {
+            normalized.push(tag.to_string());
+        }
+    }
+
+    normalized
+}
+
+pub fn current_username() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
+impl BackupVault {
+    /// Changes the tags of a snapshot and returns the new ones.
+    pub fn tag_snapshot(&mut self, snapshot_id: &String, changes: &TagChanges) -> Result<Vec<String>, BackupError> {
+        self.lock()?;
+        let result = self.tag_snapshot_locked(snapshot_id, changes);
+        self.unlock();
+
+        result
+    }
+
+    fn tag_snapshot_locked(&mut self, snapshot_id: &String, changes: &TagChanges) -> Result<Vec<String>, BackupError> {
+        let snapshot_index = match self.snapshots.iter().position(|s| s.snapshot_id == *snapshot_id) {
+            Some(snapshot_index) => snapshot_index,
+            None => {
+                println!("Snapshot not found");
+                return Err(BackupError::VaultReadError);
+            }
+        };
+
+        let tags = changes.apply(&self.snapshots[snapshot_index].tags);
+        let previous_tags = std::mem::replace(&mut self.snapshots[snapshot_index].tags, tags.clone());
+        let result = self.save_index();
+
+        if result.is_err() {
+            self.snapshots[snapshot_index].tags = previous_tags;
+        }
+
+        result.map(|_| tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(hostname: &str, tags: &[&str]) -> Snapshot {
+        snapshot_at("1", 0, hostname, tags)
+    }
+
+    fn snapshot_at(snapshot_id: &str, time: u64, hostname: &str, tags: &[&str]) -> Snapshot {
+        Snapshot {
+            snapshot_id: snapshot_id.to_string(),
+            snapshot_time: time.to_string(),
+            snapshot_files: vec![],
+            hostname: hostname.to_string(),
+            username: String::new(),
+            paths: vec![PathBuf::from("/srv/data")],
+            program_version: String::new(),
+            description: None,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn filter() {
+        let snapshot = snapshot("nas", &["daily", "db"]);
+
+        let by_host = SnapshotFilter { hosts: strings(&["laptop", "nas"]), ..Default::default() };
+        let other_host = SnapshotFilter { hosts: strings(&["laptop"]), ..Default::default() };
+        let by_tags = SnapshotFilter { tags: strings(&["db", "daily"]), ..Default::default() };
+        let missing_tag = SnapshotFilter { tags: strings(&["db", "weekly"]), ..Default::default() };
+        let by_path = SnapshotFilter { paths: vec![PathBuf::from("/srv/data")], ..Default::default() };
+        let other_path = SnapshotFilter { paths: vec![PathBuf::from("/srv/other")], ..Default::default() };
+
+        assert!(SnapshotFilter::default().matches(&snapshot));
+        assert!(by_host.matches(&snapshot));
+        assert!(!other_host.matches(&snapshot));
+        assert!(by_tags.matches(&snapshot));
+        assert!(!missing_tag.matches(&snapshot));
+        assert!(by_path.matches(&snapshot));
+        assert!(!other_path.matches(&snapshot));
+    }
+
+    #[test]
+    fn tag_changes() {
+        let tags = strings(&["daily", "db"]);
+
+        let add_remove = TagChanges { add: strings(&["keep ", "daily"]), remove: strings(&["db"]), ..Default::default() };
+        let set = TagChanges { set: Some(strings(&["weekly", ""])), add: strings(&["keep"]), ..Default::default() };
+
+        assert!(TagChanges::default().is_empty());
+        assert_eq!(add_remove.apply(&tags), strings(&["daily", "keep"]));
+        assert_eq!(set.apply(&tags), strings(&["weekly", "keep"]));
+    }
+
+    #[test]
+    fn parse_selector() {
+        let by_id = SnapshotSelector::parse("7F4C80d0").unwrap();
+        let latest_host = SnapshotSelector::parse("latest:host=nas,tag=db").unwrap();
+        let before = SnapshotSelector::parse("before:2026-01-01").unwrap();
+        let before_time = SnapshotSelector::parse("before:2026-01-01 00:00:10").unwrap();
+        let path = SnapshotSelector::parse("path:/etc").unwrap();
+
+        assert_eq!(by_id.id_prefix.as_deref(), Some("7f4c80d0"));
+        assert_eq!(latest_host.filter.hosts, strings(&["nas"]));
+        assert_eq!(latest_host.filter.tags, strings(&["db"]));
+        assert_eq!(before.before, Some(1767225600));
+        assert_eq!(before_time.before, Some(1767225610));
+        assert_eq!(path.filter.paths, vec![PathBuf::from("/etc")]);
+        assert!(SnapshotSelector::parse("latest").unwrap().id_prefix.is_none());
+        assert!(SnapshotSelector::parse("newest").is_err());
+        assert!(SnapshotSelector::parse("latest:owner=me").is_err());
+        assert!(SnapshotSelector::parse("before:yesterday").is_err());
+    }
+
+    #[test]
+    fn find() {
+        let empty = SnapshotSelector::latest().find(&[]);
+
+        let snapshots = vec![
+            snapshot_at("aa01", 10, "nas", &["daily"]),
+            snapshot_at("aa02", 30, "laptop", &["daily"]),
+            snapshot_at("bb03", 20, "nas", &["weekly"]),
+        ];
+
+        let find = |selector: &str| SnapshotSelector::parse(selector).unwrap().find(&snapshots).map(|s| s.snapshot_id.clone());
+
+        assert!(matches!(empty, Err(SelectorError::NoSnapshots)));
+        assert_eq!(find("latest").unwrap(), "aa02");
+        assert_eq!(find("latest:host=nas").unwrap(), "bb03");
+        assert_eq!(find("tag:daily").unwrap(), "aa02");
+        assert_eq!(find("before:1970-01-01 00:00:30").unwrap(), "bb03");
+        assert_eq!(find("bb").unwrap(), "bb03");
+        assert!(matches!(find("aa"), Err(SelectorError::Ambiguous(_, ids)) if ids.len() == 2));
+        assert!(matches!(find("cc"), Err(SelectorError::NotFound(_))));
+        assert!(matches!(find("tag:monthly"), Err(SelectorError::NotFound(_))));
+    }
+
+    #[test]
+    fn restore_path() {
+        let mut snapshot = snapshot("nas", &[]);
+        snapshot.paths = vec![PathBuf::from("/srv/data/nested"), PathBuf::from("/srv/data"), PathBuf::from("/etc")];
+
+        let restore_path = |file_path: &str| snapshot.restore_path(Path::new(file_path));
+
+        assert_eq!(restore_path("/srv/data/a/b.txt"), PathBuf::from("data/a/b.txt"));
+        assert_eq!(restore_path("/srv/data/nested/c.txt"), PathBuf::from("data/nested/c.txt"));
+        assert_eq!(restore_path("/etc/hosts"), PathBuf::from("etc/hosts"));
+        assert_eq!(restore_path("../../outside/d.txt"), PathBuf::from("outside/d.txt"));
+
+        snapshot.paths = vec![PathBuf::from("/srv/data"), PathBuf::from("/")];
+
+        assert_eq!(snapshot.restore_path(Path::new("/srv/data/a/b.txt")), PathBuf::from("srv/data/a/b.txt"));
+    }
+
+    #[test]
+    fn legacy_snapshot() {
+        let legacy = r#"{"snapshot_id":"1","snapshot_time":"0","snapshot_files":[]}"#;
+        let snapshot: Snapshot = serde_json::from_str(legacy).unwrap();
+
+        // Unchanged, or append-only servers would reject the index as rewriting history
+        assert_eq!(serde_json::to_string(&snapshot).unwrap(), legacy);
+    }
+}
@@ -0,0 +1,150 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::backup_vault::*;
+
+/// Where the data of a stream backup comes from.
+#[derive(Debug, Clone)]
+pub enum StreamSource {
+    Stdin,
+    /// A program and its arguments, its output is backed up
+    Command(Vec<String>),
+}
+
+impl BackupVault {
+    /// Backs up a stream as a snapshot with a single file named `file_name`, without it touching the
+    /// disk. The stream is split into blobs of at most 4 MiB, so only one of them is held in memory.
+    /// If the command fails the snapshot is not saved.
+    pub fn backup_stream(&mut self, source: &StreamSource, file_name: &Path, options: &BackupOptions) -> Result<String, BackupError> {
+        println!("Creating backup...");
+
+        self.lock()?;
+        let result = self.backup_stream_locked(source, file_name, options);
+        self.unlock();
+
+        result
+    }
+
+    fn backup_stream_locked(&mut self, source: &StreamSource, file_name: &Path, options: &BackupOptions) -> Result<String, BackupError> {
+        let mut snapshot = new_snapshot(vec![file_name.to_path_buf()], options);
+
+        let vault_file = match source {
+            StreamSource::Stdin => self.vault_add_stream(std::io::stdin().lock(), file_name, options)?,
+            StreamSource::Command(command) => {
+                let Some((program, args)) = command.split_first() else {
+                    println!("No command to back up the output of");
+                    return Err(BackupError::VaultFileOpenError);
+                };
+
+                let mut child = match Command::new(program).args(args).stdin(Stdio::null()).stdout(Stdio::piped()).spawn() {
+                    Ok(child) => child,
+                    Err(err) => {
+                        println!("Failed to run {}: {}", program, err);
+                        return Err(BackupError::VaultFileOpenError);
+                    }
+                };
+
+                let vault_file = self.vault_add_stream(child.stdout.take().unwrap(), file_name, options);
+
+                if vault_file.is_err() {
+                    let _ = child.kill();
+                }
+
+                match child.wait() {
+                    Ok(status) if status.success() => vault_file?,
+                    Ok(status) => {
+                        println!("{} failed with {}, the snapshot was not saved", program, status);
+                        return Err(BackupError::VaultFileReadError);
+                    },
+                    Err(err) => {
+                        println!("Failed to wait for {}: {}", program, err);
+                        return Err(BackupError::VaultFileReadError);
+                    }
+                }
+            }
+        };
+
+        let snapshot_id = snapshot.snapshot_id.clone();
+        snapshot.snapshot_files.push(vault_file);
+
+        self.snapshots.push(snapshot);
+        self.save_index()?;
+
+        println!("Snapshot created {}", snapshot_id);
+
+        Ok(snapshot_id)
+    }
+
+    pub(crate) fn vault_add_stream(&mut self, mut reader: impl Read, file_name: &Path, options: &BackupOptions) -> Result<VaultFile, BackupError> {
+        let name = file_name.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let mut file_hasher = blake3::Hasher::new();
+        let mut file_size = 0;
+        let mut vault_paths = vec![];
+
+        loop {
+            let mut buffer = Vec::with_capacity(BUF_SIZE);
+
+            if let Err(err) = reader.by_ref().take(BUF_SIZE as u64).read_to_end(&mut buffer) {
+                println!("Failed to read the input: {}", err);
+                return Err(BackupError::VaultFileReadError);
+            }
+
+            if buffer.is_empty() {
+                break;
+            }
+
+            file_hasher.update(&buffer);
+            file_size += buffer.len() as u64;
+
+            let blob_path = PathBuf::from(blake3::hash(&buffer).to_hex()[0..16].to_string());
+
+            // Identical content is already stored, no need to upload it again
+            if !self.has_blob(&blob_path)? && self.write_blob(&blob_path, &buffer, &name, options.compression).is_err() {
+                println!("Failed to write file: {}", blob_path.display());
+                return Err(BackupError::VaultFileCopyError);
+            }
+
+            vault_paths.push(blob_path);
+        }
+
+        Ok(VaultFile {
+            file_name: name,
+            file_hash: file_hasher.finalize().to_hex().to_string(),
+            file_path: file_name.to_path_buf(),
+            file_size,
+            vault_paths,
+            file_modified: Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()),
+            file_mode: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_vault::tests::temp_vault;
+
+    #[test]
+    fn backup_command() {
+        let (_root, mut vault) = temp_vault("stream");
+        let options = BackupOptions::default();
+        let command = |script: &str| StreamSource::Command(vec!["sh".to_string(), "-c".to_string(), script.to_string()]);
+
+        let large = vault.backup_stream(&command("head -c 5000000 /dev/zero; echo end"), Path::new("zeros.bin"), &options);
+        let failed = vault.backup_stream(&command("echo partial; exit 3"), Path::new("failed.sql"), &options);
+
+        let snapshots = vault.snapshots.len();
+        let vault_file = vault.snapshots[0].snapshot_files[0].clone();
+        let mut content = vec![];
+        vault.dump_file(&vault_file, &mut content).unwrap();
+
+        assert!(large.is_ok());
+        assert!(matches!(failed, Err(BackupError::VaultFileReadError)));
+        assert_eq!(snapshots, 1);
+        assert_eq!((vault_file.file_path, vault_file.file_size, vault_file.vault_paths.len()), (PathBuf::from("zeros.bin"), 5000004, 2));
+        assert_eq!(&content[4999990..], b"\0\0\0\0\0\0\0\0\0\0end\n");
+        assert_eq!(vault_file.file_hash, blake3::hash(&content).to_hex().to_string());
+    }
+}